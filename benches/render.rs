@@ -0,0 +1,105 @@
+// timings of whole passes through the library on the built in scenes, run with
+// cargo bench --bench render, optionally followed by part of a case's name
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rayon::prelude::*;
+use raytracer::{Accumulation, BvhBuild, RenderSettings, ScenePreset, Vec3, color, render};
+
+// the fastest of a few runs after a warm up, the slower ones are mostly other
+// things happening on the machine
+fn bench<F: FnMut()>(name: &str, runs: usize, mut f: F) {
+    if let Some(filter) = std::env::args().skip(1).find(|arg| !arg.starts_with('-')) {
+        if !name.contains(filter.as_str()) {
+            return;
+        }
+    }
+    f();
+    let best = (0..runs)
+        .map(|_| {
+            let timer = Instant::now();
+            f();
+            timer.elapsed()
+        })
+        .min()
+        .unwrap_or(Duration::from_secs(0));
+    println!("{:40} {:8.1} ms", name, best.as_secs_f64() * 1000.0);
+}
+
+// one pass of the given preset at the given size
+fn pass(preset: ScenePreset, width: usize, height: usize, samples: usize, settings: &RenderSettings) -> impl FnMut() {
+    pass_with(preset, false, width, height, samples, settings)
+}
+
+fn pass_with(preset: ScenePreset, packets: bool, width: usize, height: usize, samples: usize, settings: &RenderSettings) -> impl FnMut() {
+    let scene = preset.scene(0);
+    let camera = scene.camera.camera(width as f32 / height as f32);
+    let world = scene.world(packets, BvhBuild::Sah).unwrap();
+    let settings = RenderSettings { seed: Some(1), verbosity: 0, ..settings.clone() };
+    move || {
+        let mut accumulation = Accumulation::new(width, height);
+        render(&mut accumulation, &world, &camera, &settings, samples, &AtomicBool::new(false));
+    }
+}
+
+// the same pass with every pixel a task of its own handed out by par_bridge,
+// without the tiles, filtering or the rest of the accumulation
+fn per_pixel(preset: ScenePreset, width: usize, height: usize, samples: usize, settings: &RenderSettings) -> impl FnMut() {
+    let scene = preset.scene(0);
+    let camera = scene.camera.camera(width as f32 / height as f32);
+    let world = scene.world(false, BvhBuild::Sah).unwrap();
+    let settings = RenderSettings { seed: Some(1), verbosity: 0, ..settings.clone() };
+    move || {
+        let mut buffer = vec![Vec3::zero(); width * height];
+        buffer.iter_mut().enumerate().par_bridge().for_each(|(i, pixel)| {
+            let mut random = StdRng::seed_from_u64(i as u64);
+            let (x, y) = (i % width, height - i / width - 1);
+            for _ in 0..samples {
+                let u = (x as f32 + random.gen::<f32>()) / width as f32;
+                let v = (y as f32 + random.gen::<f32>()) / height as f32;
+                let ray = camera.ray(u, v, &mut random);
+                *pixel += color(ray, &world, &settings, &mut random).color;
+            }
+        });
+        assert!(buffer.iter().all(|pixel| pixel.is_finite()));
+    }
+}
+
+fn main() {
+    let defaults = RenderSettings::default();
+
+    // how the image is split between the threads: pixels handed out one at a
+    // time by par_bridge, then tile sizes of single pixels, the default tiles
+    // and whole rows, where 800 stands in for par_chunks_mut(width)
+    bench("split 800x600 per pixel par_bridge", 5, per_pixel(ScenePreset::Default, 800, 600, 4, &defaults));
+    for &tile_size in &[1, 32, 800] {
+        let settings = RenderSettings { tile_size, ..defaults.clone() };
+        bench(&format!("split 800x600 tile_size {}", tile_size), 5, pass(ScenePreset::Default, 800, 600, 4, &settings));
+    }
+
+    // tiles against a split into single pixels on a full hd frame
+    for &tile_size in &[1, 32] {
+        let settings = RenderSettings { tile_size, ..defaults.clone() };
+        bench(&format!("tiles 1920x1080 tile_size {}", tile_size), 3, pass(ScenePreset::Default, 1920, 1080, 1, &settings));
+    }
+
+    // long paths in a closed room, where most of the time goes into the bounces
+    bench("bounces cornell 400x400", 3, pass(ScenePreset::Cornell, 400, 400, 4, &defaults));
+
+    // the many small spheres one at a time and eight at once
+    for &packets in &[false, true] {
+        bench(&format!("packets random-many {}", packets), 10, pass_with(ScenePreset::RandomMany, packets, 400, 225, 4, &defaults));
+    }
+
+    // a generator passed down against looking up the thread's every time
+    bench("rng 1M points passed", 5, || {
+        let mut random = StdRng::seed_from_u64(0);
+        let sum = (0..1_000_000).fold(Vec3::zero(), |sum, _| sum + Vec3::random_in_unit_sphere(&mut random));
+        assert!(sum.is_finite());
+    });
+    bench("rng 1M points thread_rng", 5, || {
+        let sum = (0..1_000_000).fold(Vec3::zero(), |sum, _| sum + Vec3::random_in_unit_sphere_thread_rng());
+        assert!(sum.is_finite());
+    });
+}
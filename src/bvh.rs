@@ -0,0 +1,221 @@
+use rand::RngCore;
+use crate::geometry::{Vec3, Ray, Hit, Aabb, Hittable};
+
+pub(crate) struct BvhNode {
+    bounding_box: Aabb,
+    left: Box<dyn Hittable>,
+    right: Box<dyn Hittable>,
+}
+
+// how BvhNode::build splits the objects: the median is quick to build, the
+// surface area heuristic takes longer but gives better trees when the objects
+// are spread unevenly
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BvhBuild {
+    #[default]
+    Median,
+    Sah,
+}
+
+impl std::str::FromStr for BvhBuild {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<BvhBuild, String> {
+        match s {
+            "median" => Ok(BvhBuild::Median),
+            "sah" => Ok(BvhBuild::Sah),
+            _ => Err(format!("unknown bvh build: {}", s)),
+        }
+    }
+}
+
+const SAH_BINS: usize = 16;
+
+impl BvhNode {
+    // splits the objects at the median along the longest axis of their centers,
+    // or where the surface area heuristic says, every object must have a
+    // bounding box
+    pub(crate) fn build(mut objects: Vec<Box<dyn Hittable>>, build: BvhBuild) -> Box<dyn Hittable> {
+        if objects.len() == 1 {
+            return objects.remove(0);
+        }
+
+        let center = |o: &dyn Hittable| o.bounding_box().map(|b| b.center()).unwrap_or_else(Vec3::zero);
+        let first = center(objects[0].as_ref());
+        let centers = objects
+            .iter()
+            .map(|o| center(o.as_ref()))
+            .fold(Aabb::new(first, first), |b, c| Aabb::surrounding_box(b, Aabb::new(c, c)));
+        let extent = centers.max - centers.min;
+        let axis = if extent.x > extent.y && extent.x > extent.z {
+            0
+        } else if extent.y > extent.z {
+            1
+        } else {
+            2
+        };
+
+        let sah_split = match build {
+            BvhBuild::Sah => BvhNode::sah_split(&objects, &centers),
+            BvhBuild::Median => None,
+        };
+        let (objects, right) = match sah_split {
+            Some((axis, bin)) => {
+                let bin_of = |c: Vec3| BvhNode::bin(c, &centers, axis);
+                objects.into_iter().partition(|o| bin_of(center(o.as_ref())) < bin)
+            }
+            None => {
+                objects.sort_by(|a, b| center(a.as_ref())[axis].partial_cmp(&center(b.as_ref())[axis]).unwrap_or(std::cmp::Ordering::Equal));
+                let right = objects.split_off(objects.len() / 2);
+                (objects, right)
+            }
+        };
+        let left = BvhNode::build(objects, build);
+        let right = BvhNode::build(right, build);
+
+        let bounding_box = match (left.bounding_box(), right.bounding_box()) {
+            (Some(a), Some(b)) => Aabb::surrounding_box(a, b),
+            (Some(a), None) | (None, Some(a)) => a,
+            (None, None) => Aabb::new(Vec3::zero(), Vec3::zero()),
+        };
+
+        Box::new(BvhNode { bounding_box, left, right })
+    }
+
+    // which of the SAH_BINS slices of the centers' box along the axis a center is in
+    fn bin(center: Vec3, centers: &Aabb, axis: usize) -> usize {
+        let extent = centers.max[axis] - centers.min[axis];
+        (((center[axis] - centers.min[axis]) / extent * SAH_BINS as f32) as usize).min(SAH_BINS - 1)
+    }
+
+    // the centers are sorted into bins along every axis, and every boundary
+    // between bins is a candidate split costing the area of each side's box
+    // times the objects in it; the cheapest wins as the axis and the first bin
+    // of the right side, None when all centers are in the same place
+    fn sah_split(objects: &[Box<dyn Hittable>], centers: &Aabb) -> Option<(usize, usize)> {
+        let mut best: Option<(f32, usize, usize)> = None;
+        for axis in 0..3 {
+            if centers.max[axis] - centers.min[axis] <= 0.0 {
+                continue;
+            }
+
+            let mut bins: [(Option<Aabb>, usize); SAH_BINS] = [(None, 0); SAH_BINS];
+            for o in objects {
+                let Some(b) = o.bounding_box() else { continue };
+                let bin = &mut bins[BvhNode::bin(b.center(), centers, axis)];
+                bin.0 = Some(bin.0.map_or(b, |a| Aabb::surrounding_box(a, b)));
+                bin.1 += 1;
+            }
+
+            // the areas and counts of everything right of each boundary, then a
+            // sweep from the left adds up the other side
+            let merge = |a: Option<Aabb>, b: Option<Aabb>| match (a, b) {
+                (Some(a), Some(b)) => Some(Aabb::surrounding_box(a, b)),
+                (a, b) => a.or(b),
+            };
+            let mut right = [(0.0, 0); SAH_BINS];
+            let (mut right_box, mut right_count) = (None, 0);
+            for i in (1..SAH_BINS).rev() {
+                right_box = merge(right_box, bins[i].0);
+                right_count += bins[i].1;
+                right[i] = (right_box.map_or(0.0, |b| b.surface_area()), right_count);
+            }
+            let (mut left_box, mut left_count) = (None, 0);
+            for i in 1..SAH_BINS {
+                left_box = merge(left_box, bins[i - 1].0);
+                left_count += bins[i - 1].1;
+                let (right_area, right_count) = right[i];
+                if left_count == 0 || right_count == 0 {
+                    continue;
+                }
+                let cost = left_box.map_or(0.0, |b| b.surface_area()) * left_count as f32 + right_area * right_count as f32;
+                if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                    best = Some((cost, axis, i));
+                }
+            }
+        }
+        best.map(|(_, axis, bin)| (axis, bin))
+    }
+}
+
+impl Hittable for BvhNode {
+    fn hit_test(&self, ray: &Ray, t_min: f32, t_max: f32, random: &mut dyn RngCore) -> Option<Hit<'_>> {
+        if !self.bounding_box.hit(ray, t_min, t_max) {
+            return None;
+        }
+
+        let left = self.left.hit_test(ray, t_min, t_max, random);
+        let right = self.right.hit_test(ray, t_min, left.as_ref().map_or(t_max, |h| h.t), random);
+        right.or(left)
+    }
+
+    // the same traversal as hit_test, a node counts once its box is tested
+    fn nodes_visited(&self, ray: &Ray, t_min: f32, t_max: f32, random: &mut dyn RngCore) -> (usize, Option<f32>) {
+        if !self.bounding_box.hit(ray, t_min, t_max) {
+            return (1, None);
+        }
+
+        let (left_nodes, left) = self.left.nodes_visited(ray, t_min, t_max, random);
+        let (right_nodes, right) = self.right.nodes_visited(ray, t_min, left.unwrap_or(t_max), random);
+        (1 + left_nodes + right_nodes, right.or(left))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.bounding_box)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use crate::geometry::Sphere;
+    use crate::material::{Material, Texture};
+
+    // spheres told apart by the red of their albedo
+    fn spheres(count: usize) -> Vec<Box<dyn Hittable>> {
+        let mut random = StdRng::seed_from_u64(86);
+        (0..count)
+            .map(|i| {
+                let center = Vec3::new(random.gen_range(-10.0, 10.0), random.gen_range(-10.0, 10.0), random.gen_range(-10.0, 10.0));
+                let material = Material::Lambertian { albedo: Texture::Solid(Vec3::new(i as f32, 0.0, 0.0)) };
+                Box::new(Sphere::new(center, random.gen_range(0.1, 1.0), material)) as Box<dyn Hittable>
+            })
+            .collect()
+    }
+
+    fn id(hit: &Hit) -> usize {
+        match hit.material {
+            Material::Lambertian { albedo: Texture::Solid(c) } => c.x as usize,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn sah_and_median_trees_agree_with_every_sphere_tested() {
+        let linear = spheres(300);
+        let median = BvhNode::build(spheres(300), BvhBuild::Median);
+        let sah = BvhNode::build(spheres(300), BvhBuild::Sah);
+        let mut random = StdRng::seed_from_u64(1);
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let origin = Vec3::new(random.gen_range(-15.0, 15.0), random.gen_range(-15.0, 15.0), random.gen_range(-15.0, 15.0));
+            let target = Vec3::new(random.gen_range(-10.0, 10.0), random.gen_range(-10.0, 10.0), random.gen_range(-10.0, 10.0));
+            let ray = Ray::new(origin, target - origin);
+
+            let expected = linear
+                .iter()
+                .filter_map(|o| o.hit_test(&ray, 0.001, f32::MAX, &mut random))
+                .min_by(|a, b| a.t.partial_cmp(&b.t).unwrap())
+                .map(|hit| (hit.t, id(&hit)));
+            for tree in [&median, &sah] {
+                let found = tree.hit_test(&ray, 0.001, f32::MAX, &mut random).map(|hit| (hit.t, id(&hit)));
+                assert_eq!(found, expected);
+            }
+            hits += expected.is_some() as usize;
+        }
+        // most rays are aimed into the cloud, and some miss
+        assert!(hits > 500 && hits < 2000, "{} hits", hits);
+    }
+}
@@ -0,0 +1,1919 @@
+use std::error::Error;
+use rand::{Rng, RngCore};
+use wide::{CmpGe, CmpGt, CmpLt, f32x8};
+use std::collections::HashMap;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use crate::clamped;
+use crate::material::{Texture, Material};
+use crate::render::STATS;
+
+// written and read as [x, y, z] in scene files
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(feature = "serde", serde(from = "[f32; 3]", into = "[f32; 3]"))]
+pub struct Vec3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl From<[f32; 3]> for Vec3 {
+    fn from(v: [f32; 3]) -> Vec3 {
+        Vec3::new(v[0], v[1], v[2])
+    }
+}
+
+impl From<Vec3> for [f32; 3] {
+    fn from(v: Vec3) -> [f32; 3] {
+        [v.x, v.y, v.z]
+    }
+}
+
+impl std::fmt::Display for Vec3 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "({}, {}, {})", self.x, self.y, self.z)
+    }
+}
+
+impl Vec3 {
+    pub fn new(x: f32, y: f32, z: f32) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    pub fn splat(v: f32) -> Vec3 {
+        Vec3::new(v, v, v)
+    }
+
+    pub(crate) fn to_array(self) -> [f32; 3] {
+        [self.x, self.y, self.z]
+    }
+
+    pub fn random_in_unit_sphere(random: &mut impl Rng) -> Vec3 {
+        loop {
+            let x = random.gen_range(-1.0, 1.0);
+            let y = random.gen_range(-1.0, 1.0);
+            let z = random.gen_range(-1.0, 1.0);
+            let p = Vec3::new(x, y, z);
+
+            if p.squared_length() < 1.0 {
+                return p
+            }
+        }
+    }
+
+    // the same on the thread's generator, for callers that don't keep one of
+    // their own, renders pass theirs down instead of looking it up every time
+    pub fn random_in_unit_sphere_thread_rng() -> Vec3 {
+        Vec3::random_in_unit_sphere(&mut rand::thread_rng())
+    }
+
+    fn random_in_unit_disk(random: &mut impl Rng) -> Vec3 {
+        loop {
+            let x = random.gen_range(-1.0, 1.0);
+            let y = random.gen_range(-1.0, 1.0);
+            let p = Vec3::new(x, y, 0.0);
+
+            if p.squared_length() < 1.0 {
+                return p
+            }
+        }
+    }
+
+    // uniformly inside a regular polygon with that many corners on the unit
+    // circle, one pointing up, or inside the disk for fewer than three
+    pub(crate) fn random_in_polygon(blades: u32, random: &mut impl Rng) -> Vec3 {
+        if blades < 3 {
+            return Vec3::random_in_unit_disk(random);
+        }
+
+        // the triangles between the center and each side have the same area,
+        // so pick one and then a point in it, folding the far half back in
+        let step = 2.0 * std::f32::consts::PI / blades as f32;
+        let angle = std::f32::consts::FRAC_PI_2 + step * random.gen_range(0, blades) as f32;
+        let a = Vec3::new(angle.cos(), angle.sin(), 0.0);
+        let b = Vec3::new((angle + step).cos(), (angle + step).sin(), 0.0);
+        let (mut r1, mut r2): (f32, f32) = (random.gen(), random.gen());
+        if r1 + r2 > 1.0 {
+            r1 = 1.0 - r1;
+            r2 = 1.0 - r2;
+        }
+        r1 * a + r2 * b
+    }
+
+    // cosine weighted direction on the hemisphere around +z
+    pub(crate) fn random_cosine_direction(random: &mut impl Rng) -> Vec3 {
+        Vec3::cosine_direction(random.gen(), random.gen())
+    }
+
+    // the same for given numbers between 0 and 1, which can be stratified
+    pub(crate) fn cosine_direction(r1: f32, r2: f32) -> Vec3 {
+        let phi = 2.0 * std::f32::consts::PI * r1;
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        Vec3::new(cos_phi * r2.sqrt(), sin_phi * r2.sqrt(), (1.0 - r2).sqrt())
+    }
+
+    // directions around +z distributed like a phong lobe, cos^exponent of the angle
+    pub(crate) fn random_phong_direction(exponent: f32, random: &mut impl Rng) -> Vec3 {
+        let r1: f32 = random.gen();
+        let r2: f32 = random.gen();
+        let phi = 2.0 * std::f32::consts::PI * r1;
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        let cos_alpha = r2.powf(1.0 / (exponent + 1.0));
+        let sin_alpha = (1.0 - cos_alpha * cos_alpha).max(0.0).sqrt();
+        Vec3::new(cos_phi * sin_alpha, sin_phi * sin_alpha, cos_alpha)
+    }
+
+    // microfacet normals around +z distributed like the ggx lobe for alpha,
+    // the square of the roughness
+    pub(crate) fn random_ggx_direction(alpha: f32, random: &mut impl Rng) -> Vec3 {
+        let r1: f32 = random.gen();
+        let r2: f32 = random.gen();
+        let phi = 2.0 * std::f32::consts::PI * r1;
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        let cos_theta = ((1.0 - r2) / (1.0 + (alpha * alpha - 1.0) * r2)).max(0.0).sqrt();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        Vec3::new(cos_phi * sin_theta, sin_phi * sin_theta, cos_theta)
+    }
+
+    // the same normals with the slopes of alpha_u along x and alpha_v along y,
+    // ggx stays ggx when its slopes are stretched
+    pub(crate) fn random_anisotropic_ggx_direction(alpha_u: f32, alpha_v: f32, random: &mut impl Rng) -> Vec3 {
+        let r1: f32 = random.gen();
+        let r2: f32 = random.gen();
+        let phi = 2.0 * std::f32::consts::PI * r1;
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        let tan_theta = (r2 / (1.0 - r2)).sqrt();
+        Vec3::new(alpha_u * tan_theta * cos_phi, alpha_v * tan_theta * sin_phi, 1.0).unit_vector()
+    }
+
+    pub fn zero() -> Vec3 {
+        Vec3::new(0.00, 0.0, 0.0)
+    }
+
+    fn add(self, other: Vec3) -> Vec3 {
+        Vec3 {
+            x: self.x + other.x,
+            y: self.y + other.y,
+            z: self.z + other.z,
+        }
+    }
+
+    fn subtract(self, other: Vec3) -> Vec3 {
+        Vec3 {
+            x: self.x - other.x,
+            y: self.y - other.y,
+            z: self.z - other.z,
+        }
+    }
+
+    fn negate(self) -> Vec3 {
+        Vec3 {
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+        }
+    }
+
+    fn multiply(self, other: Vec3) -> Vec3 {
+        Vec3 {
+            x: self.x * other.x,
+            y: self.y * other.y,
+            z: self.z * other.z,
+        }
+    }
+
+    fn multiply_scalar(self, scalar: f32) -> Vec3 {
+        Vec3 {
+            x: self.x * scalar,
+            y: self.y * scalar,
+            z: self.z * scalar,
+        }
+    }
+
+    fn div(self, other: Vec3) -> Vec3 {
+        Vec3 {
+            x: self.x / other.x,
+            y: self.y / other.y,
+            z: self.z / other.z,
+        }
+    }
+
+    fn div_scalar(self, scalar: f32) -> Vec3 {
+        Vec3 {
+            x: self.x / scalar,
+            y: self.y / scalar,
+            z: self.z / scalar,
+        }
+    }
+
+    pub fn dot(self, other: Vec3) -> f32 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn cross(self, other: Vec3) -> Vec3 {
+        Vec3 {
+            x: self.y * other.z - self.z * other.y,
+            y: self.z * other.x - self.x * other.z,
+            z: self.x * other.y - self.y * other.x,
+        }
+    }
+
+    pub fn length(self) -> f32 {
+        (self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
+    }
+
+    pub fn squared_length(self) -> f32 {
+        self.x * self.x + self.y * self.y + self.z * self.z
+    }
+
+    pub(crate) fn set(&mut self, axis: usize, value: f32) {
+        match axis {
+            0 => self.x = value,
+            1 => self.y = value,
+            _ => self.z = value,
+        }
+    }
+
+    pub fn max_component(self) -> f32 {
+        self.x.max(self.y).max(self.z)
+    }
+
+    pub fn near_zero(self) -> bool {
+        let epsilon = 1e-8;
+        self.x.abs() < epsilon && self.y.abs() < epsilon && self.z.abs() < epsilon
+    }
+
+    // every component within eps of the other's, for comparing computed values
+    pub fn approx_eq(self, other: Vec3, eps: f32) -> bool {
+        (self.x - other.x).abs() < eps && (self.y - other.y).abs() < eps && (self.z - other.z).abs() < eps
+    }
+
+    // no component is NaN or infinite
+    pub fn is_finite(self) -> bool {
+        self.x.is_finite() && self.y.is_finite() && self.z.is_finite()
+    }
+
+    pub fn unit_vector(self) -> Vec3 {
+        self.div_scalar(self.length())
+    }
+
+    pub fn reflect(self, normal: Vec3) -> Vec3 {
+        self - 2.0 * self.dot(normal) * normal
+    }
+
+    // Snell's law, None on total internal reflection
+    pub fn refract(self, normal: Vec3, etai_over_etat: f32) -> Option<Vec3> {
+        let uv = self.unit_vector();
+        let dt = uv.dot(normal);
+        let discriminant = 1.0 - etai_over_etat * etai_over_etat * (1.0 - dt * dt);
+        if discriminant > 0.0 {
+            Some(etai_over_etat * (uv - normal * dt) - normal * discriminant.sqrt())
+        } else {
+            None
+        }
+    }
+
+    // rotation around a unit axis by Rodrigues' formula, angle in degrees
+    pub fn rotated(self, axis: Vec3, degrees: f32) -> Vec3 {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        self * cos + axis.cross(self) * sin + axis * axis.dot(self) * (1.0 - cos)
+    }
+
+    // e raised to each component
+    pub(crate) fn exp(self) -> Vec3 {
+        Vec3::new(self.x.exp(), self.y.exp(), self.z.exp())
+    }
+
+    // clamps every component to [0, 1], NaN becomes 0
+    pub(crate) fn clamp01(self) -> Vec3 {
+        fn clamp(v: f32) -> f32 {
+            if v.is_nan() { 0.0 } else { clamped(v, 0.0, 1.0) }
+        }
+
+        Vec3::new(clamp(self.x), clamp(self.y), clamp(self.z))
+    }
+
+    pub fn lerp(from: Vec3, to: Vec3, v: f32) -> Vec3 {
+        let v = clamped(v, 0.0, 1.0);
+        from * (1.0 - v) + to * v
+    }
+}
+
+impl std::ops::Add<Self> for Vec3 {
+    type Output = Vec3;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        self.add(rhs)
+    }
+}
+
+impl std::ops::Neg for Vec3 {
+    type Output = Vec3;
+
+    fn neg(self) -> Self::Output {
+        self.negate()
+    }
+}
+
+impl std::ops::Sub<Self> for Vec3 {
+    type Output = Vec3;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.subtract(rhs)
+    }
+}
+
+impl std::ops::Mul<Self> for Vec3 {
+    type Output = Vec3;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        self.multiply(rhs)
+    }
+}
+
+impl std::ops::Mul<f32> for Vec3 {
+    type Output = Vec3;
+
+    fn mul(self, rhs: f32) -> Self::Output {
+        self.multiply_scalar(rhs)
+    }
+}
+
+impl std::ops::Mul<Vec3> for f32 {
+    type Output = Vec3;
+
+    fn mul(self, rhs: Vec3) -> Self::Output {
+        rhs.multiply_scalar(self)
+    }
+}
+
+impl std::ops::Div<Self> for Vec3 {
+    type Output = Vec3;
+
+    fn div(self, rhs: Self) -> Self::Output {
+        self.div(rhs)
+    }
+}
+
+impl std::ops::Div<f32> for Vec3 {
+    type Output = Vec3;
+
+    fn div(self, rhs: f32) -> Self::Output {
+        self.div_scalar(rhs)
+    }
+}
+
+impl std::ops::AddAssign for Vec3 {
+    fn add_assign(&mut self, rhs: Vec3) {
+        *self = self.add(rhs);
+    }
+}
+
+impl std::ops::SubAssign for Vec3 {
+    fn sub_assign(&mut self, rhs: Vec3) {
+        *self = self.subtract(rhs);
+    }
+}
+
+impl std::ops::MulAssign for Vec3 {
+    fn mul_assign(&mut self, rhs: Vec3) {
+        *self = self.multiply(rhs);
+    }
+}
+
+impl std::ops::MulAssign<f32> for Vec3 {
+    fn mul_assign(&mut self, rhs: f32) {
+        *self = self.multiply_scalar(rhs);
+    }
+}
+
+// the same on references, for code that only has borrowed vectors
+impl std::ops::Add<&Vec3> for &Vec3 {
+    type Output = Vec3;
+
+    fn add(self, rhs: &Vec3) -> Self::Output {
+        (*self).add(*rhs)
+    }
+}
+
+impl std::ops::Sub<&Vec3> for &Vec3 {
+    type Output = Vec3;
+
+    fn sub(self, rhs: &Vec3) -> Self::Output {
+        self.subtract(*rhs)
+    }
+}
+
+impl std::ops::Mul<&Vec3> for &Vec3 {
+    type Output = Vec3;
+
+    fn mul(self, rhs: &Vec3) -> Self::Output {
+        self.multiply(*rhs)
+    }
+}
+
+impl std::ops::Mul<f32> for &Vec3 {
+    type Output = Vec3;
+
+    fn mul(self, rhs: f32) -> Self::Output {
+        self.multiply_scalar(rhs)
+    }
+}
+
+impl std::ops::AddAssign<&Vec3> for Vec3 {
+    fn add_assign(&mut self, rhs: &Vec3) {
+        *self = self.add(*rhs);
+    }
+}
+
+impl std::ops::Index<usize> for Vec3 {
+    type Output = f32;
+
+    fn index(&self, axis: usize) -> &f32 {
+        match axis {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Vec3 axis out of range: {}", axis),
+        }
+    }
+}
+
+// orthonormal basis around w
+pub(crate) struct Onb {
+    pub(crate) u: Vec3,
+    pub(crate) v: Vec3,
+    pub(crate) w: Vec3,
+}
+
+impl Onb {
+    pub(crate) fn from_w(n: Vec3) -> Onb {
+        let w = n.unit_vector();
+        let a = if w.x.abs() > 0.9 { Vec3::new(0.0, 1.0, 0.0) } else { Vec3::new(1.0, 0.0, 0.0) };
+        let v = w.cross(a).unit_vector();
+        let u = w.cross(v);
+        Onb { u, v, w }
+    }
+
+    pub(crate) fn local(&self, a: Vec3) -> Vec3 {
+        a.x * self.u + a.y * self.v + a.z * self.w
+    }
+}
+
+pub struct Ray {
+    origin: Vec3,
+    pub(crate) direction: Vec3,
+    // the only color channel a path still carries after dispersive glass
+    pub(crate) channel: Option<usize>,
+}
+
+impl Ray {
+    pub fn new(origin: Vec3, direction: Vec3) -> Ray {
+        Ray { origin, direction, channel: None }
+    }
+
+    pub fn origin(&self) -> Vec3 {
+        self.origin
+    }
+
+    pub fn direction(&self) -> Vec3 {
+        self.direction
+    }
+
+    pub fn point_at_parameter(&self, t: f32) -> Vec3 {
+        self.origin + self.direction * t
+    }
+}
+
+pub(crate) struct Hit<'a> {
+    pub(crate) t: f32,
+    pub(crate) position: Vec3,
+    pub(crate) normal: Vec3,
+    pub(crate) front_face: bool,
+    // surface coordinates for texture lookups
+    pub(crate) u: f32,
+    pub(crate) v: f32,
+    // the direction u grows in along the surface, zero where it isn't known
+    pub(crate) tangent: Vec3,
+    pub(crate) material: &'a Material,
+}
+
+impl<'a> Hit<'a> {
+    // the stored normal always points against the incoming ray,
+    // front_face tells whether that is the outward normal or not
+    fn with_face_normal(t: f32, position: Vec3, outward_normal: Vec3, ray: &Ray, material: &'a Material) -> Hit<'a> {
+        let front_face = ray.direction().dot(outward_normal) < 0.0;
+        let normal = if front_face { outward_normal } else { -outward_normal };
+        Hit { t, position, normal, front_face, u: 0.0, v: 0.0, tangent: Vec3::zero(), material }
+    }
+
+    // a normal that differs from the geometry's, on the same side of the surface
+    // as the outward normal the hit was made with, renormalized as blending
+    // unit vectors shortens them
+    fn with_shading_normal(self, normal: Vec3, outward_normal: Vec3) -> Hit<'a> {
+        let normal = normal.unit_vector();
+        let normal = if normal.dot(outward_normal) < 0.0 { -normal } else { normal };
+        Hit { normal: if self.front_face { normal } else { -normal }, ..self }
+    }
+
+    fn with_uv(self, u: f32, v: f32) -> Hit<'a> {
+        Hit { u, v, ..self }
+    }
+
+    fn with_tangent(self, tangent: Vec3) -> Hit<'a> {
+        Hit { tangent, ..self }
+    }
+
+    // a frame around the normal with u along the tangent, made up where the
+    // surface has none
+    pub(crate) fn tangent_frame(&self, tangent: Vec3) -> Onb {
+        let w = self.normal;
+        let u = tangent - w * w.dot(tangent);
+        let u = if u.near_zero() { Onb::from_w(w).u } else { u.unit_vector() };
+        Onb { u, v: w.cross(u), w }
+    }
+
+    // hits on normal mapped materials continue with the normal bent by the map
+    // and the material underneath, other hits are left as they are
+    pub(crate) fn with_normal_map(self) -> Hit<'a> {
+        let (normal_map, material) = match self.material {
+            Material::NormalMapped { normal_map, material } => (normal_map, &**material),
+            _ => return self,
+        };
+
+        let frame = self.tangent_frame(self.tangent);
+
+        // images are loaded as squared colors, the map stores plain components
+        let c = normal_map.value(self.u, self.v);
+        let local = Vec3::new(c.x.sqrt(), c.y.sqrt(), c.z.sqrt()) * 2.0 - Vec3::splat(1.0);
+        let normal = frame.local(local).unit_vector();
+        Hit { normal, tangent: frame.u, material, ..self }.with_normal_map()
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct Aabb {
+    pub(crate) min: Vec3,
+    pub(crate) max: Vec3,
+}
+
+impl Aabb {
+    pub(crate) fn new(min: Vec3, max: Vec3) -> Aabb {
+        Aabb { min, max }
+    }
+
+    // slab method: intersect the ray's t interval with the interval between
+    // each pair of axis aligned planes, a hit remains if it isn't empty
+    pub(crate) fn hit(&self, ray: &Ray, mut t_min: f32, mut t_max: f32) -> bool {
+        for axis in 0..3 {
+            let inverse_direction = 1.0 / ray.direction()[axis];
+            let mut t0 = (self.min[axis] - ray.origin()[axis]) * inverse_direction;
+            let mut t1 = (self.max[axis] - ray.origin()[axis]) * inverse_direction;
+            if inverse_direction < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+
+            t_min = if t0 > t_min { t0 } else { t_min };
+            t_max = if t1 < t_max { t1 } else { t_max };
+            if t_max <= t_min {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    pub(crate) fn surrounding_box(a: Aabb, b: Aabb) -> Aabb {
+        Aabb {
+            min: Vec3::new(a.min.x.min(b.min.x), a.min.y.min(b.min.y), a.min.z.min(b.min.z)),
+            max: Vec3::new(a.max.x.max(b.max.x), a.max.y.max(b.max.y), a.max.z.max(b.max.z)),
+        }
+    }
+
+    pub(crate) fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub(crate) fn surface_area(&self) -> f32 {
+        let extent = self.max - self.min;
+        2.0 * (extent.x * extent.y + extent.y * extent.z + extent.z * extent.x)
+    }
+}
+
+pub(crate) trait Hittable: Send + Sync {
+    // the generator is for objects that are hit at random, like volumes
+    fn hit_test(&self, ray: &Ray, t_min: f32, t_max: f32, random: &mut dyn RngCore) -> Option<Hit<'_>>;
+
+    // None for unbounded objects like planes, which are kept out of the bvh
+    fn bounding_box(&self) -> Option<Aabb>;
+
+    // for objects that can be used as lights: the density over solid angle of
+    // random_direction picking a direction from origin, where hits closer than
+    // t_min don't count, like for the rays that were traced
+    fn pdf_value(&self, _origin: Vec3, _direction: Vec3, _t_min: f32, _random: &mut dyn RngCore) -> f32 {
+        0.0
+    }
+
+    fn random_direction(&self, _origin: Vec3, _random: &mut dyn RngCore) -> Vec3 {
+        Vec3::new(1.0, 0.0, 0.0)
+    }
+
+    // how many bvh nodes a ray visits before it settles on the closest hit, for
+    // the bvh heat mode, along with where that hit is
+    fn nodes_visited(&self, ray: &Ray, t_min: f32, t_max: f32, random: &mut dyn RngCore) -> (usize, Option<f32>) {
+        (0, self.hit_test(ray, t_min, t_max, random).map(|hit| hit.t))
+    }
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct Sphere {
+    pub center: Vec3,
+    pub radius: f32,
+    pub material: Material
+}
+
+impl Sphere {
+    pub fn new(center: Vec3, radius: f32, material: Material) -> Sphere {
+        Sphere { center, radius, material }
+    }
+
+    fn hit_at(&self, ray: &Ray, t: f32) -> Hit<'_> {
+        let point = ray.point_at_parameter(t);
+        let outward_normal = (point - self.center) / self.radius;
+        let (u, v) = Sphere::uv(outward_normal);
+        // around the y axis in the direction u grows in
+        let tangent = Vec3::new(outward_normal.z, 0.0, -outward_normal.x);
+        Hit::with_face_normal(t, point, outward_normal, ray, &self.material).with_uv(u, v).with_tangent(tangent)
+    }
+
+    // longitude and latitude of a point on the unit sphere mapped to [0, 1],
+    // u starts at -x and goes around through +z, v goes from -y up to +y
+    pub(crate) fn uv(p: Vec3) -> (f32, f32) {
+        let theta = clamped(-p.y, -1.0, 1.0).acos();
+        let phi = (-p.z).atan2(p.x) + std::f32::consts::PI;
+        (phi / (2.0 * std::f32::consts::PI), theta / std::f32::consts::PI)
+    }
+}
+
+impl Hittable for Sphere {
+    fn hit_test(&self, ray: &Ray, t_min: f32, t_max: f32, _random: &mut dyn RngCore) -> Option<Hit<'_>> {
+        STATS.record_sphere_test();
+
+        // solves |origin + t * direction - center|^2 = radius^2 for t, using
+        // b = half of the linear coefficient so the factors of 2 and 4 cancel
+        let oc = ray.origin() - self.center;
+        let a = ray.direction().dot(ray.direction());
+        if a < 1e-12 {
+            return None;
+        }
+        let half_b = oc.dot(ray.direction());
+        let c = oc.dot(oc) - self.radius * self.radius;
+        let discriminant = half_b * half_b - a * c;
+        if discriminant < 0.0 {
+            return None;
+        }
+
+        // try the nearer root first, the farther one is where rays starting
+        // inside the sphere leave it
+        let sqrtd = discriminant.sqrt();
+        let mut t = (-half_b - sqrtd) / a;
+        if t <= t_min || t >= t_max {
+            t = (-half_b + sqrtd) / a;
+            if t <= t_min || t >= t_max {
+                return None;
+            }
+        }
+
+        Some(self.hit_at(ray, t))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let extent = Vec3::new(self.radius, self.radius, self.radius);
+        Some(Aabb::new(self.center - extent, self.center + extent))
+    }
+
+    // directions are picked in the cone the sphere covers, or all around
+    // from inside it
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, t_min: f32, random: &mut dyn RngCore) -> f32 {
+        if self.hit_test(&Ray::new(origin, direction), t_min, f32::MAX, random).is_none() {
+            return 0.0;
+        }
+        let distance_squared = (self.center - origin).squared_length();
+        let radius_squared = self.radius * self.radius;
+        if distance_squared <= radius_squared {
+            return 1.0 / (4.0 * std::f32::consts::PI);
+        }
+        // 1 - cos written so it doesn't round to zero for far away spheres
+        let sin_squared = radius_squared / distance_squared;
+        let one_minus_cos = sin_squared / (1.0 + (1.0 - sin_squared).sqrt());
+        1.0 / (2.0 * std::f32::consts::PI * one_minus_cos)
+    }
+
+    fn random_direction(&self, origin: Vec3, mut random: &mut dyn RngCore) -> Vec3 {
+        let to_center = self.center - origin;
+        let distance_squared = to_center.squared_length();
+        let radius_squared = self.radius * self.radius;
+        if distance_squared <= radius_squared {
+            return Vec3::random_in_unit_sphere(&mut random).unit_vector();
+        }
+        let cos_theta_max = (1.0 - radius_squared / distance_squared).sqrt();
+        let r1: f32 = random.gen();
+        let r2: f32 = random.gen();
+        let z = 1.0 + r2 * (cos_theta_max - 1.0);
+        let (sin_phi, cos_phi) = (2.0 * std::f32::consts::PI * r1).sin_cos();
+        let sin_theta = (1.0 - z * z).max(0.0).sqrt();
+        Onb::from_w(to_center).local(Vec3::new(cos_phi * sin_theta, sin_phi * sin_theta, z))
+    }
+}
+
+// up to PACKET_SIZE nearby spheres with their centers and radii in the lanes
+// of simd registers, so a ray is tested against all of them in one go
+const PACKET_SIZE: usize = 8;
+
+pub(crate) struct SpherePacket {
+    center_x: f32x8,
+    center_y: f32x8,
+    center_z: f32x8,
+    radius: f32x8,
+    spheres: Vec<Sphere>,
+}
+
+impl SpherePacket {
+    fn new(spheres: Vec<Sphere>) -> SpherePacket {
+        // unused lanes get a sphere that is never hit
+        let (mut x, mut y, mut z, mut radius) = ([0.0; PACKET_SIZE], [0.0; PACKET_SIZE], [0.0; PACKET_SIZE], [-1.0; PACKET_SIZE]);
+        for (i, s) in spheres.iter().enumerate() {
+            x[i] = s.center.x;
+            y[i] = s.center.y;
+            z[i] = s.center.z;
+            radius[i] = s.radius;
+        }
+        SpherePacket { center_x: x.into(), center_y: y.into(), center_z: z.into(), radius: radius.into(), spheres }
+    }
+
+    // splits the spheres at the median along the axis their centers spread the
+    // most until they fit into packets, so packets stay small for the bvh
+    pub(crate) fn build(mut spheres: Vec<Sphere>) -> Vec<SpherePacket> {
+        if spheres.len() <= PACKET_SIZE {
+            return vec![SpherePacket::new(spheres)];
+        }
+
+        let first = spheres[0].center;
+        let centers = spheres.iter().fold(Aabb::new(first, first), |b, s| Aabb::surrounding_box(b, Aabb::new(s.center, s.center)));
+        let extent = centers.max - centers.min;
+        let axis = if extent.x > extent.y && extent.x > extent.z {
+            0
+        } else if extent.y > extent.z {
+            1
+        } else {
+            2
+        };
+
+        spheres.sort_by(|a, b| a.center[axis].partial_cmp(&b.center[axis]).unwrap_or(std::cmp::Ordering::Equal));
+        let right = spheres.split_off(spheres.len() / 2);
+        let mut packets = SpherePacket::build(spheres);
+        packets.extend(SpherePacket::build(right));
+        packets
+    }
+}
+
+impl Hittable for SpherePacket {
+    fn hit_test(&self, ray: &Ray, t_min: f32, t_max: f32, _random: &mut dyn RngCore) -> Option<Hit<'_>> {
+        STATS.record_sphere_tests(self.spheres.len());
+
+        // the same quadratic as Sphere::hit_test on all lanes at once, rays
+        // that miss every sphere stop before the square roots
+        let origin = ray.origin();
+        let direction = ray.direction();
+        let a = direction.dot(direction);
+        if a < 1e-12 {
+            return None;
+        }
+        let (dx, dy, dz) = (f32x8::splat(direction.x), f32x8::splat(direction.y), f32x8::splat(direction.z));
+        let ocx = f32x8::splat(origin.x) - self.center_x;
+        let ocy = f32x8::splat(origin.y) - self.center_y;
+        let ocz = f32x8::splat(origin.z) - self.center_z;
+        let half_b = ocx * dx + ocy * dy + ocz * dz;
+        let c = ocx * ocx + ocy * ocy + ocz * ocz - self.radius * self.radius;
+        let discriminant = half_b * half_b - f32x8::splat(a) * c;
+        let candidates = discriminant.cmp_ge(f32x8::ZERO) & self.radius.cmp_gt(f32x8::ZERO);
+        if candidates.none() {
+            return None;
+        }
+
+        let sqrtd = discriminant.max(f32x8::ZERO).sqrt();
+        let inverse_a = f32x8::splat(1.0 / a);
+        let near = (-half_b - sqrtd) * inverse_a;
+        let far = (-half_b + sqrtd) * inverse_a;
+        let (t_min, t_max) = (f32x8::splat(t_min), f32x8::splat(t_max));
+        let near_inside = near.cmp_gt(t_min) & near.cmp_lt(t_max);
+        let far_inside = far.cmp_gt(t_min) & far.cmp_lt(t_max);
+        let hit = candidates & (near_inside | far_inside);
+        let ts = hit.blend(near_inside.blend(near, far), f32x8::splat(f32::INFINITY)).to_array();
+
+        let (closest, t) = ts.iter().enumerate().fold((0, f32::INFINITY), |(ci, ct), (i, &t)| if t < ct { (i, t) } else { (ci, ct) });
+        if t == f32::INFINITY {
+            return None;
+        }
+        Some(self.spheres[closest].hit_at(ray, t))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.spheres
+            .iter()
+            .filter_map(|s| s.bounding_box())
+            .reduce(Aabb::surrounding_box)
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct Plane {
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub material: Material
+}
+
+impl Plane {
+    pub(crate) fn new(point: Vec3, normal: Vec3, material: Material) -> Plane {
+        Plane { point, normal: normal.unit_vector(), material }
+    }
+}
+
+impl Hittable for Plane {
+    fn hit_test(&self, ray: &Ray, t_min: f32, t_max: f32, _random: &mut dyn RngCore) -> Option<Hit<'_>> {
+        let denominator = self.normal.dot(ray.direction());
+
+        // rays parallel to the plane never hit it
+        if denominator.abs() < 1e-6 {
+            return None;
+        }
+
+        let t = (self.point - ray.origin()).dot(self.normal) / denominator;
+        if t < t_max && t > t_min {
+            Some(Hit::with_face_normal(t, ray.point_at_parameter(t), self.normal, ray, &self.material))
+        } else {
+            None
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        None
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct Triangle {
+    pub a: Vec3,
+    pub b: Vec3,
+    pub c: Vec3,
+    pub material: Material,
+    // the normals at a, b and c, blended across the triangle so meshes shade
+    // smoothly, flat when not given
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub normals: Option<[Vec3; 3]>,
+}
+
+impl Triangle {
+    fn new(a: Vec3, b: Vec3, c: Vec3, material: Material) -> Triangle {
+        Triangle { a, b, c, material, normals: None }
+    }
+}
+
+impl Hittable for Triangle {
+    // Möller–Trumbore: solve for t and the barycentric coordinates u, v at once
+    fn hit_test(&self, ray: &Ray, t_min: f32, t_max: f32, _random: &mut dyn RngCore) -> Option<Hit<'_>> {
+        let edge1 = self.b - self.a;
+        let edge2 = self.c - self.a;
+        let h = ray.direction().cross(edge2);
+        let determinant = edge1.dot(h);
+
+        // the ray is parallel to the triangle
+        if determinant.abs() < 1e-8 {
+            return None;
+        }
+
+        let inverse_determinant = 1.0 / determinant;
+        let s = ray.origin() - self.a;
+        let u = inverse_determinant * s.dot(h);
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+
+        let q = s.cross(edge1);
+        let v = inverse_determinant * ray.direction().dot(q);
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+
+        let t = inverse_determinant * edge2.dot(q);
+        if t < t_max && t > t_min {
+            let outward_normal = edge1.cross(edge2).unit_vector();
+            let hit = Hit::with_face_normal(t, ray.point_at_parameter(t), outward_normal, ray, &self.material);
+            match self.normals {
+                // u and v are the weights of b and c, the rest is a's
+                Some([na, nb, nc]) => Some(hit.with_shading_normal((1.0 - u - v) * na + u * nb + v * nc, outward_normal)),
+                None => Some(hit),
+            }
+        } else {
+            None
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // padded so triangles lying in an axis aligned plane don't get a flat box
+        let padding = Vec3::new(1e-4, 1e-4, 1e-4);
+        let min = Vec3::new(self.a.x.min(self.b.x).min(self.c.x), self.a.y.min(self.b.y).min(self.c.y), self.a.z.min(self.b.z).min(self.c.z));
+        let max = Vec3::new(self.a.x.max(self.b.x).max(self.c.x), self.a.y.max(self.b.y).max(self.c.y), self.a.z.max(self.b.z).max(self.c.z));
+        Some(Aabb::new(min - padding, max + padding))
+    }
+}
+
+// a rectangle perpendicular to one of the axes, min and max are its corners and
+// agree on that axis
+#[derive(Clone)]
+pub(crate) struct AxisRect {
+    axis: usize,
+    min: Vec3,
+    max: Vec3,
+    normal: Vec3,
+    pub(crate) material: Material,
+}
+
+impl AxisRect {
+    fn new(axis: usize, min: Vec3, max: Vec3, normal: Vec3, material: Material) -> AxisRect {
+        AxisRect { axis, min, max, normal, material }
+    }
+
+    // the in-plane axes in the order of the texture coordinates
+    fn plane_axes(&self) -> (usize, usize) {
+        match self.axis {
+            0 => (1, 2),
+            1 => (0, 2),
+            _ => (0, 1),
+        }
+    }
+}
+
+impl Hittable for AxisRect {
+    fn hit_test(&self, ray: &Ray, t_min: f32, t_max: f32, _random: &mut dyn RngCore) -> Option<Hit<'_>> {
+        let direction = ray.direction()[self.axis];
+        if direction.abs() < 1e-8 {
+            return None;
+        }
+
+        let t = (self.min[self.axis] - ray.origin()[self.axis]) / direction;
+        if t <= t_min || t >= t_max {
+            return None;
+        }
+
+        let point = ray.point_at_parameter(t);
+        let (a, b) = self.plane_axes();
+        if point[a] < self.min[a] || point[a] > self.max[a] || point[b] < self.min[b] || point[b] > self.max[b] {
+            return None;
+        }
+
+        let u = (point[a] - self.min[a]) / (self.max[a] - self.min[a]);
+        let v = (point[b] - self.min[b]) / (self.max[b] - self.min[b]);
+        let mut tangent = Vec3::zero();
+        tangent.set(a, 1.0);
+        Some(Hit::with_face_normal(t, point, self.normal, ray, &self.material).with_uv(u, v).with_tangent(tangent))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // padded along the axis so the box isn't flat
+        let mut padding = Vec3::zero();
+        padding.set(self.axis, 1e-4);
+        Some(Aabb::new(self.min - padding, self.max + padding))
+    }
+
+    // points are picked evenly over the area, which turns into a density over
+    // solid angle of distance squared over cosine times area
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, t_min: f32, random: &mut dyn RngCore) -> f32 {
+        let hit = match self.hit_test(&Ray::new(origin, direction), t_min, f32::MAX, random) {
+            Some(hit) => hit,
+            None => return 0.0,
+        };
+        let (a, b) = self.plane_axes();
+        let area = (self.max[a] - self.min[a]) * (self.max[b] - self.min[b]);
+        let distance_squared = hit.t * hit.t * direction.squared_length();
+        let cosine = direction.dot(self.normal).abs() / direction.length();
+        distance_squared / (cosine * area)
+    }
+
+    fn random_direction(&self, origin: Vec3, random: &mut dyn RngCore) -> Vec3 {
+        let (a, b) = self.plane_axes();
+        let mut point = self.min;
+        point.set(a, self.min[a] + random.gen::<f32>() * (self.max[a] - self.min[a]));
+        point.set(b, self.min[b] + random.gen::<f32>() * (self.max[b] - self.min[b]));
+        point - origin
+    }
+}
+
+// rectangles for scene files, k is where they sit on the remaining axis, they
+// face along it unless flipped, which matters to one sided lights
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct RectXY {
+    pub x0: f32,
+    pub x1: f32,
+    pub y0: f32,
+    pub y1: f32,
+    pub k: f32,
+    pub material: Material,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub flip: bool,
+}
+
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct RectXZ {
+    pub x0: f32,
+    pub x1: f32,
+    pub z0: f32,
+    pub z1: f32,
+    pub k: f32,
+    pub material: Material,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub flip: bool,
+}
+
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct RectYZ {
+    pub y0: f32,
+    pub y1: f32,
+    pub z0: f32,
+    pub z1: f32,
+    pub k: f32,
+    pub material: Material,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub flip: bool,
+}
+
+impl RectXY {
+    pub(crate) fn rect(self) -> AxisRect {
+        let side = if self.flip { -1.0 } else { 1.0 };
+        AxisRect::new(2, Vec3::new(self.x0, self.y0, self.k), Vec3::new(self.x1, self.y1, self.k), Vec3::new(0.0, 0.0, side), self.material)
+    }
+}
+
+impl RectXZ {
+    pub(crate) fn rect(self) -> AxisRect {
+        let side = if self.flip { -1.0 } else { 1.0 };
+        AxisRect::new(1, Vec3::new(self.x0, self.k, self.z0), Vec3::new(self.x1, self.k, self.z1), Vec3::new(0.0, side, 0.0), self.material)
+    }
+}
+
+impl RectYZ {
+    pub(crate) fn rect(self) -> AxisRect {
+        let side = if self.flip { -1.0 } else { 1.0 };
+        AxisRect::new(0, Vec3::new(self.k, self.y0, self.z0), Vec3::new(self.k, self.y1, self.z1), Vec3::new(side, 0.0, 0.0), self.material)
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct Cuboid {
+    pub min: Vec3,
+    pub max: Vec3,
+    pub material: Material,
+    // in scene files cuboids can be turned around the y axis by that many
+    // degrees and then moved
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub rotate_y: f32,
+    #[cfg_attr(feature = "serde", serde(default = "Vec3::zero"))]
+    pub translate: Vec3,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) faces: Vec<AxisRect>,
+}
+
+impl Cuboid {
+    pub fn new(min: Vec3, max: Vec3, material: Material) -> Cuboid {
+        let mut faces = Vec::with_capacity(6);
+        for axis in 0..3 {
+            // the opposite faces perpendicular to this axis, with outward normals
+            let mut normal = Vec3::zero();
+            normal.set(axis, 1.0);
+            let mut near_max = max;
+            near_max.set(axis, min[axis]);
+            let mut far_min = min;
+            far_min.set(axis, max[axis]);
+            faces.push(AxisRect::new(axis, min, near_max, -normal, material.clone()));
+            faces.push(AxisRect::new(axis, far_min, max, normal, material.clone()));
+        }
+        Cuboid { min, max, material, rotate_y: 0.0, translate: Vec3::zero(), faces }
+    }
+}
+
+impl Hittable for Cuboid {
+    fn hit_test(&self, ray: &Ray, t_min: f32, t_max: f32, random: &mut dyn RngCore) -> Option<Hit<'_>> {
+        let mut closest_t = t_max;
+        let mut result = None;
+        for face in &self.faces {
+            if let Some(hit) = face.hit_test(ray, t_min, closest_t, random) {
+                closest_t = hit.t;
+                result = Some(hit);
+            }
+        }
+        result
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::new(self.min, self.max))
+    }
+}
+
+// a cylinder standing on the disk around base and reaching height along axis
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct Cylinder {
+    pub base: Vec3,
+    pub axis: Vec3,
+    pub radius: f32,
+    pub height: f32,
+    pub material: Material,
+    #[cfg_attr(feature = "serde", serde(default = "Cylinder::default_capped"))]
+    pub capped: bool,
+}
+
+impl Cylinder {
+    pub(crate) fn new(base: Vec3, axis: Vec3, radius: f32, height: f32, material: Material, capped: bool) -> Cylinder {
+        Cylinder { base, axis: axis.unit_vector(), radius, height, material, capped }
+    }
+
+    #[cfg(feature = "serde")]
+    fn default_capped() -> bool {
+        true
+    }
+}
+
+impl Hittable for Cylinder {
+    fn hit_test(&self, ray: &Ray, t_min: f32, t_max: f32, _random: &mut dyn RngCore) -> Option<Hit<'_>> {
+        let mut closest_t = t_max;
+        let mut result = None;
+
+        // the side is the quadratic of the infinite cylinder, with the parts of
+        // the ray and the offset along the axis taken out
+        let oc = ray.origin() - self.base;
+        let direction = ray.direction() - ray.direction().dot(self.axis) * self.axis;
+        let offset = oc - oc.dot(self.axis) * self.axis;
+        let a = direction.dot(direction);
+        if a > 1e-12 {
+            let half_b = offset.dot(direction);
+            let c = offset.dot(offset) - self.radius * self.radius;
+            let discriminant = half_b * half_b - a * c;
+            if discriminant >= 0.0 {
+                let sqrtd = discriminant.sqrt();
+                for t in [(-half_b - sqrtd) / a, (-half_b + sqrtd) / a] {
+                    if t <= t_min || t >= closest_t {
+                        continue;
+                    }
+                    let point = ray.point_at_parameter(t);
+                    let along = (point - self.base).dot(self.axis);
+                    if along < 0.0 || along > self.height {
+                        continue;
+                    }
+                    let outward_normal = (point - self.base - along * self.axis) / self.radius;
+                    closest_t = t;
+                    result = Some(Hit::with_face_normal(t, point, outward_normal, ray, &self.material));
+                    break;
+                }
+            }
+        }
+
+        if self.capped {
+            let denominator = ray.direction().dot(self.axis);
+            if denominator.abs() > 1e-8 {
+                for (center, outward_normal) in [(self.base, -self.axis), (self.base + self.height * self.axis, self.axis)] {
+                    let t = (center - ray.origin()).dot(self.axis) / denominator;
+                    if t <= t_min || t >= closest_t {
+                        continue;
+                    }
+                    let point = ray.point_at_parameter(t);
+                    if (point - center).squared_length() <= self.radius * self.radius {
+                        closest_t = t;
+                        result = Some(Hit::with_face_normal(t, point, outward_normal, ray, &self.material));
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // how far the end disks reach along each axis
+        let top = self.base + self.height * self.axis;
+        let a = self.axis;
+        let extent = self.radius * Vec3::new((1.0 - a.x * a.x).max(0.0).sqrt(), (1.0 - a.y * a.y).max(0.0).sqrt(), (1.0 - a.z * a.z).max(0.0).sqrt());
+        let min = Vec3::new(self.base.x.min(top.x), self.base.y.min(top.y), self.base.z.min(top.z));
+        let max = Vec3::new(self.base.x.max(top.x), self.base.y.max(top.y), self.base.z.max(top.z));
+        Some(Aabb::new(min - extent, max + extent))
+    }
+}
+
+// a flat round disk, for round lights and the like
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct Disk {
+    pub center: Vec3,
+    pub normal: Vec3,
+    pub radius: f32,
+    pub material: Material,
+}
+
+impl Disk {
+    pub(crate) fn new(center: Vec3, normal: Vec3, radius: f32, material: Material) -> Disk {
+        Disk { center, normal: normal.unit_vector(), radius, material }
+    }
+}
+
+impl Hittable for Disk {
+    fn hit_test(&self, ray: &Ray, t_min: f32, t_max: f32, _random: &mut dyn RngCore) -> Option<Hit<'_>> {
+        let denominator = ray.direction().dot(self.normal);
+        if denominator.abs() < 1e-8 {
+            return None;
+        }
+        let t = (self.center - ray.origin()).dot(self.normal) / denominator;
+        if t <= t_min || t >= t_max {
+            return None;
+        }
+        let point = ray.point_at_parameter(t);
+        let offset = point - self.center;
+        if offset.squared_length() > self.radius * self.radius {
+            return None;
+        }
+        // polar coordinates: u goes around, v goes out from the center
+        let basis = Onb::from_w(self.normal);
+        let angle = offset.dot(basis.v).atan2(offset.dot(basis.u));
+        let u = (angle + std::f32::consts::PI) / (2.0 * std::f32::consts::PI);
+        let v = offset.length() / self.radius;
+        Some(Hit::with_face_normal(t, point, self.normal, ray, &self.material).with_uv(u, v))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // same reach as a cylinder's end disk, padded so it never goes flat
+        let n = self.normal;
+        let extent = self.radius * Vec3::new((1.0 - n.x * n.x).max(0.0).sqrt(), (1.0 - n.y * n.y).max(0.0).sqrt(), (1.0 - n.z * n.z).max(0.0).sqrt()) + Vec3::splat(1e-4);
+        Some(Aabb::new(self.center - extent, self.center + extent))
+    }
+
+    // like rectangles, points are picked evenly over the area
+    fn pdf_value(&self, origin: Vec3, direction: Vec3, t_min: f32, random: &mut dyn RngCore) -> f32 {
+        let hit = match self.hit_test(&Ray::new(origin, direction), t_min, f32::MAX, random) {
+            Some(hit) => hit,
+            None => return 0.0,
+        };
+        let area = std::f32::consts::PI * self.radius * self.radius;
+        let distance_squared = hit.t * hit.t * direction.squared_length();
+        let cosine = direction.dot(self.normal).abs() / direction.length();
+        distance_squared / (cosine * area)
+    }
+
+    fn random_direction(&self, origin: Vec3, random: &mut dyn RngCore) -> Vec3 {
+        // the square root keeps the points from bunching up in the middle
+        let r = self.radius * random.gen::<f32>().sqrt();
+        let (sin_phi, cos_phi) = (2.0 * std::f32::consts::PI * random.gen::<f32>()).sin_cos();
+        let point = self.center + Onb::from_w(self.normal).local(Vec3::new(r * cos_phi, r * sin_phi, 0.0));
+        point - origin
+    }
+}
+
+// moves an object without touching its geometry
+pub(crate) struct Translate {
+    offset: Vec3,
+    object: Box<dyn Hittable>,
+}
+
+impl Translate {
+    pub(crate) fn new(object: Box<dyn Hittable>, offset: Vec3) -> Translate {
+        Translate { offset, object }
+    }
+}
+
+impl Hittable for Translate {
+    fn hit_test(&self, ray: &Ray, t_min: f32, t_max: f32, random: &mut dyn RngCore) -> Option<Hit<'_>> {
+        let moved = Ray::new(ray.origin() - self.offset, ray.direction());
+        let mut hit = self.object.hit_test(&moved, t_min, t_max, random)?;
+        hit.position += self.offset;
+        Some(hit)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.object.bounding_box().map(|b| Aabb::new(b.min + self.offset, b.max + self.offset))
+    }
+}
+
+// turns an object around the y axis, rays are turned the other way into the
+// object's space and hits turned back
+pub(crate) struct RotateY {
+    sin: f32,
+    cos: f32,
+    object: Box<dyn Hittable>,
+    bbox: Option<Aabb>,
+}
+
+impl RotateY {
+    pub(crate) fn new(object: Box<dyn Hittable>, degrees: f32) -> RotateY {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+
+        // the box around all eight rotated corners
+        let bbox = object.bounding_box().map(|b| {
+            let mut min = Vec3::splat(f32::INFINITY);
+            let mut max = Vec3::splat(f32::NEG_INFINITY);
+            for corner in 0..8 {
+                let x = if corner & 1 == 0 { b.min.x } else { b.max.x };
+                let y = if corner & 2 == 0 { b.min.y } else { b.max.y };
+                let z = if corner & 4 == 0 { b.min.z } else { b.max.z };
+                let p = Vec3::new(cos * x + sin * z, y, -sin * x + cos * z);
+                for axis in 0..3 {
+                    min.set(axis, min[axis].min(p[axis]));
+                    max.set(axis, max[axis].max(p[axis]));
+                }
+            }
+            Aabb::new(min, max)
+        });
+
+        RotateY { sin, cos, object, bbox }
+    }
+
+    fn to_object(&self, v: Vec3) -> Vec3 {
+        Vec3::new(self.cos * v.x - self.sin * v.z, v.y, self.sin * v.x + self.cos * v.z)
+    }
+
+    fn to_world(&self, v: Vec3) -> Vec3 {
+        Vec3::new(self.cos * v.x + self.sin * v.z, v.y, -self.sin * v.x + self.cos * v.z)
+    }
+}
+
+impl Hittable for RotateY {
+    fn hit_test(&self, ray: &Ray, t_min: f32, t_max: f32, random: &mut dyn RngCore) -> Option<Hit<'_>> {
+        let rotated = Ray::new(self.to_object(ray.origin()), self.to_object(ray.direction()));
+        let mut hit = self.object.hit_test(&rotated, t_min, t_max, random)?;
+        hit.position = self.to_world(hit.position);
+        hit.normal = self.to_world(hit.normal);
+        hit.tangent = self.to_world(hit.tangent);
+        Some(hit)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bbox
+    }
+}
+
+// parses the materials of a wavefront mtl file by name: diffuse from Kd, a
+// phong highlight from Ks and Ns and glass from d or Tr below full opacity
+fn load_mtl(path: &str) -> Result<HashMap<String, Material>, Box<dyn Error>> {
+    struct Entry {
+        name: String,
+        diffuse: Vec3,
+        specular: Vec3,
+        shininess: f32,
+        opacity: f32,
+        refraction_index: f32,
+    }
+
+    impl Entry {
+        fn material(&self) -> Material {
+            if self.opacity < 1.0 {
+                Material::Dielectric { refraction_index: self.refraction_index, dispersion: 0.0, absorption: Vec3::zero() }
+            } else if self.specular.max_component() > 0.0 {
+                Material::Phong { diffuse: self.diffuse, specular: self.specular, shininess: self.shininess }
+            } else {
+                Material::Lambertian { albedo: Texture::Solid(self.diffuse) }
+            }
+        }
+    }
+
+    let mut materials = HashMap::new();
+    let mut entry: Option<Entry> = None;
+    for (line_number, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let error = || format!("{}:{}: malformed line: {}", path, line_number + 1, line);
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        if keyword == "newmtl" {
+            if let Some(entry) = entry.take() {
+                materials.insert(entry.name.clone(), entry.material());
+            }
+            entry = Some(Entry {
+                name: tokens.collect::<Vec<_>>().join(" "),
+                diffuse: Vec3::splat(0.8),
+                specular: Vec3::zero(),
+                shininess: 0.0,
+                opacity: 1.0,
+                refraction_index: 1.5,
+            });
+            continue;
+        }
+
+        // everything else that is used sets a color or a number of the current
+        // material, colors can also be a single gray value
+        let entry = match (&mut entry, keyword) {
+            (Some(entry), "Kd" | "Ks" | "Ns" | "d" | "Tr" | "Ni") => entry,
+            _ => continue,
+        };
+        let values: Vec<f32> = tokens.map(|t| t.parse()).collect::<Result<_, _>>().map_err(|_| error())?;
+        let color = || match values[..] {
+            [r, g, b, ..] => Ok(Vec3::new(r, g, b)),
+            [v] => Ok(Vec3::splat(v)),
+            _ => Err(error()),
+        };
+        let scalar = || values.first().copied().ok_or_else(error);
+        match keyword {
+            "Kd" => entry.diffuse = color()?,
+            "Ks" => entry.specular = color()?,
+            "Ns" => entry.shininess = scalar()?,
+            "d" => entry.opacity = scalar()?,
+            "Tr" => entry.opacity = 1.0 - scalar()?,
+            _ => entry.refraction_index = scalar()?,
+        }
+    }
+    if let Some(entry) = entry {
+        materials.insert(entry.name.clone(), entry.material());
+    }
+
+    Ok(materials)
+}
+
+// parses the vertices and faces of a wavefront obj file, faces with more than
+// three vertices are split into a triangle fan, groups with a material from
+// the obj's mtl files get that one and the rest the given material
+fn load_obj(path: &str, material: Material, warnings: &mut Vec<String>) -> Result<Vec<Triangle>, Box<dyn Error>> {
+    let mut vertices = Vec::new();
+    let mut normals = Vec::new();
+    let mut triangles = Vec::new();
+    // the vertex indices of the triangles that get their normals averaged from
+    // the faces around their corners, which all faces count toward
+    let mut corners = Vec::new();
+    let mut smoothed = Vec::new();
+    let mut smoothing = true;
+    let mut materials = HashMap::new();
+    let mut current = material.clone();
+
+    for (line_number, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let error = || format!("{}:{}: malformed line: {}", path, line_number + 1, line);
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("v") => {
+                let v: Vec<f32> = tokens.take(3).map(|t| t.parse()).collect::<Result<_, _>>().map_err(|_| error())?;
+                if v.len() != 3 {
+                    return Err(error().into());
+                }
+                vertices.push(Vec3::new(v[0], v[1], v[2]));
+            }
+            Some("vn") => {
+                let n: Vec<f32> = tokens.take(3).map(|t| t.parse()).collect::<Result<_, _>>().map_err(|_| error())?;
+                if n.len() != 3 {
+                    return Err(error().into());
+                }
+                normals.push(Vec3::new(n[0], n[1], n[2]));
+            }
+            // faces after "s off" stay flat unless they have normals of their own
+            Some("s") => smoothing = !matches!(tokens.next(), Some("off") | Some("0")),
+            Some("f") => {
+                // indices are 1-based, negative ones count back from the last one,
+                // texture indices between the slashes are ignored
+                let resolve = |index: &str, count: usize| -> Result<usize, String> {
+                    let index: i64 = index.parse().map_err(|_| error())?;
+                    let index = if index < 0 { count as i64 + index } else { index - 1 };
+                    if index < 0 || index as usize >= count { Err(error()) } else { Ok(index as usize) }
+                };
+                let face = tokens
+                    .map(|t| {
+                        let mut parts = t.split('/');
+                        let vertex = resolve(parts.next().unwrap_or(""), vertices.len())?;
+                        let normal = match parts.nth(1) {
+                            Some(n) if !n.is_empty() => Some(normals[resolve(n, normals.len())?]),
+                            _ => None,
+                        };
+                        Ok((vertex, normal))
+                    })
+                    .collect::<Result<Vec<(usize, Option<Vec3>)>, String>>()?;
+                if face.len() < 3 {
+                    return Err(error().into());
+                }
+                for i in 1..face.len() - 1 {
+                    let corner = [face[0], face[i], face[i + 1]];
+                    let mut triangle = Triangle::new(vertices[corner[0].0], vertices[corner[1].0], vertices[corner[2].0], current.clone());
+                    if let [(_, Some(na)), (_, Some(nb)), (_, Some(nc))] = corner {
+                        triangle.normals = Some([na, nb, nc]);
+                    } else if smoothing {
+                        smoothed.push(triangles.len());
+                    }
+                    corners.push([corner[0].0, corner[1].0, corner[2].0]);
+                    triangles.push(triangle);
+                }
+            }
+            // mtl files are found next to the obj, one that can't be read leaves
+            // its groups with the given material and a warning for the caller
+            Some("mtllib") => {
+                let directory = std::path::Path::new(path).parent().unwrap_or_else(|| std::path::Path::new(""));
+                for name in tokens {
+                    let mtl_path = directory.join(name);
+                    let mtl_path = mtl_path.to_string_lossy();
+                    match load_mtl(&mtl_path) {
+                        Ok(loaded) => materials.extend(loaded),
+                        Err(e) => warnings.push(format!("Failed to load {}: {}", mtl_path, e)),
+                    }
+                }
+            }
+            Some("usemtl") => {
+                let name = tokens.collect::<Vec<_>>().join(" ");
+                current = materials.get(&name).cloned().unwrap_or_else(|| material.clone());
+            }
+            _ => {}
+        }
+    }
+
+    // the cross product of two edges is twice the area long, so bigger faces
+    // weigh more in the average
+    if !smoothed.is_empty() {
+        let mut vertex_normals = vec![Vec3::zero(); vertices.len()];
+        for (t, corner) in triangles.iter().zip(&corners) {
+            let face_normal = (t.b - t.a).cross(t.c - t.a);
+            for &i in corner {
+                vertex_normals[i] += face_normal;
+            }
+        }
+        for i in smoothed {
+            let [a, b, c] = corners[i].map(|v| vertex_normals[v]);
+            // a vertex shared by faces that cancel out has no direction to blend
+            if [a, b, c].iter().all(|n| n.squared_length() > 0.0) {
+                triangles[i].normals = Some([a.unit_vector(), b.unit_vector(), c.unit_vector()]);
+            }
+        }
+    }
+
+    Ok(triangles)
+}
+
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct Mesh {
+    pub path: String,
+    pub material: Material,
+    // where the bottom center of the mesh is placed
+    #[cfg_attr(feature = "serde", serde(default = "Vec3::zero"))]
+    pub position: Vec3,
+    // the largest extent of the mesh after scaling
+    #[cfg_attr(feature = "serde", serde(default = "Mesh::default_size"))]
+    pub size: f32,
+}
+
+impl Mesh {
+    pub fn default_size() -> f32 {
+        1.0
+    }
+
+    pub(crate) fn triangles(&self, warnings: &mut Vec<String>) -> Result<Vec<Triangle>, Box<dyn Error>> {
+        let mut triangles = load_obj(&self.path, self.material.clone(), warnings)?;
+        let bounds = triangles
+            .iter()
+            .filter_map(|t| t.bounding_box())
+            .fold(None, |b: Option<Aabb>, t| Some(b.map_or(t, |b| Aabb::surrounding_box(b, t))))
+            .ok_or_else(|| format!("{}: no faces", self.path))?;
+
+        let extent = bounds.max - bounds.min;
+        let scale = self.size / extent.x.max(extent.y).max(extent.z);
+        let bottom_center = Vec3::new(bounds.center().x, bounds.min.y, bounds.center().z);
+        let place = |v: Vec3| (v - bottom_center) * scale + self.position;
+        for t in &mut triangles {
+            t.a = place(t.a);
+            t.b = place(t.b);
+            t.c = place(t.c);
+        }
+
+        Ok(triangles)
+    }
+}
+
+// a volume of uniform density inside a closed boundary, rays pass through it
+// or scatter somewhere inside with a probability that grows with the distance
+pub(crate) struct ConstantMedium {
+    boundary: Box<dyn Hittable>,
+    density: f32,
+    phase_material: Material,
+}
+
+impl ConstantMedium {
+    fn new(boundary: Box<dyn Hittable>, density: f32, phase_material: Material) -> ConstantMedium {
+        ConstantMedium { boundary, density, phase_material }
+    }
+}
+
+impl Hittable for ConstantMedium {
+    fn hit_test(&self, ray: &Ray, t_min: f32, t_max: f32, random: &mut dyn RngCore) -> Option<Hit<'_>> {
+        // where the ray enters and leaves the boundary, also when it starts inside
+        let entry = self.boundary.hit_test(ray, f32::NEG_INFINITY, f32::INFINITY, random)?;
+        let exit = self.boundary.hit_test(ray, entry.t + 0.0001, f32::INFINITY, random)?;
+        let t_entry = entry.t.max(t_min).max(0.0);
+        let t_exit = exit.t.min(t_max);
+        if t_entry >= t_exit {
+            return None;
+        }
+
+        let ray_length = ray.direction().length();
+        let distance_inside = (t_exit - t_entry) * ray_length;
+        let hit_distance = -random.gen::<f32>().ln() / self.density;
+        if hit_distance > distance_inside {
+            return None;
+        }
+
+        // the normal is arbitrary, the phase material doesn't use it
+        let t = t_entry + hit_distance / ray_length;
+        Some(Hit::with_face_normal(t, ray.point_at_parameter(t), Vec3::new(1.0, 0.0, 0.0), ray, &self.phase_material))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.boundary.bounding_box()
+    }
+}
+
+// the shapes a medium can fill in scene files
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "lowercase"))]
+pub enum Boundary {
+    Sphere { center: Vec3, radius: f32 },
+    Cuboid { min: Vec3, max: Vec3 },
+}
+
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct Medium {
+    pub boundary: Boundary,
+    pub density: f32,
+    pub albedo: Texture,
+}
+
+impl Medium {
+    pub(crate) fn constant_medium(self) -> ConstantMedium {
+        let phase_material = Material::Isotropic { albedo: self.albedo };
+        let boundary: Box<dyn Hittable> = match self.boundary {
+            Boundary::Sphere { center, radius } => Box::new(Sphere::new(center, radius, phase_material.clone())),
+            Boundary::Cuboid { min, max } => Box::new(Cuboid::new(min, max, phase_material.clone())),
+        };
+        ConstantMedium::new(boundary, self.density, phase_material)
+    }
+}
+
+// distributions of directions to bounce into, value is the density over
+// solid angle of the directions generate picks
+pub(crate) trait Pdf {
+    fn value(&self, direction: Vec3, random: &mut dyn RngCore) -> f32;
+
+    fn generate(&self, random: &mut dyn RngCore) -> Vec3;
+}
+
+// toward one of a list of objects, picked evenly, as seen from origin, with
+// the ray epsilon the paths are traced with
+pub(crate) struct HittablePdf<'a> {
+    origin: Vec3,
+    epsilon: f32,
+    objects: &'a [Box<dyn Hittable>],
+}
+
+impl<'a> HittablePdf<'a> {
+    pub(crate) fn new(origin: Vec3, epsilon: f32, objects: &'a [Box<dyn Hittable>]) -> HittablePdf<'a> {
+        HittablePdf { origin, epsilon, objects }
+    }
+}
+
+impl<'a> Pdf for HittablePdf<'a> {
+    fn value(&self, direction: Vec3, random: &mut dyn RngCore) -> f32 {
+        let sum: f32 = self.objects.iter().map(|o| o.pdf_value(self.origin, direction, self.epsilon, random)).sum();
+        sum / self.objects.len() as f32
+    }
+
+    fn generate(&self, random: &mut dyn RngCore) -> Vec3 {
+        let index = random.gen_range(0, self.objects.len());
+        self.objects[index].random_direction(self.origin, random)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn gray() -> Material {
+        Material::Lambertian { albedo: Texture::Solid(Vec3::splat(0.5)) }
+    }
+
+    fn hit(object: &dyn Hittable, origin: Vec3, direction: Vec3) -> Option<Hit<'_>> {
+        object.hit_test(&Ray::new(origin, direction), 0.001, f32::MAX, &mut StdRng::seed_from_u64(0))
+    }
+
+    #[test]
+    fn box_misses_clips_and_passes_through() {
+        let aabb = Aabb::new(Vec3::splat(-1.0), Vec3::splat(1.0));
+        let z = Vec3::new(0.0, 0.0, 1.0);
+        assert!(!aabb.hit(&Ray::new(Vec3::new(0.0, 2.0, -5.0), z), 0.001, f32::MAX));
+        assert!(aabb.hit(&Ray::new(Vec3::new(0.0, 0.0, -5.0), z), 0.001, f32::MAX));
+        // diagonally across the corner at x = 1, z = -1
+        assert!(aabb.hit(&Ray::new(Vec3::new(0.0, 0.0, -1.5), Vec3::new(1.0, 0.0, 1.0)), 0.001, f32::MAX));
+        assert!(!aabb.hit(&Ray::new(Vec3::new(0.5, 0.0, -2.0), Vec3::new(1.0, 0.0, 1.0)), 0.001, f32::MAX));
+        // the box is behind the ray's start
+        assert!(!aabb.hit(&Ray::new(Vec3::new(0.0, 0.0, 5.0), z), 0.001, f32::MAX));
+    }
+
+    #[test]
+    fn sphere_miss_graze_and_center() {
+        let sphere = Sphere::new(Vec3::zero(), 1.0, gray());
+        let z = Vec3::new(0.0, 0.0, 1.0);
+        assert!(hit(&sphere, Vec3::new(0.0, 1.01, -5.0), z).is_none());
+
+        let graze = hit(&sphere, Vec3::new(0.0, 1.0, -5.0), z).unwrap();
+        assert_eq!(graze.t, 5.0);
+        assert_approx_eq!(graze.position, Vec3::new(0.0, 1.0, 0.0));
+
+        // the nearer of the two roots
+        let center = hit(&sphere, Vec3::new(0.0, 0.0, -5.0), z).unwrap();
+        assert_eq!(center.t, 4.0);
+        assert_approx_eq!(center.normal, Vec3::new(0.0, 0.0, -1.0));
+        assert!(center.front_face);
+    }
+
+    #[test]
+    fn triangle_centroid_and_just_outside_the_edges() {
+        let triangle = Triangle::new(Vec3::zero(), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), gray());
+        let z = Vec3::new(0.0, 0.0, 1.0);
+        let centroid = hit(&triangle, Vec3::new(1.0 / 3.0, 1.0 / 3.0, -1.0), z).unwrap();
+        assert_approx_eq!(centroid.position, Vec3::new(1.0 / 3.0, 1.0 / 3.0, 0.0));
+        assert_approx_eq!(Vec3::splat(centroid.t), Vec3::splat(1.0));
+        // the winding faces +z, so this ray comes from behind
+        assert!(!centroid.front_face);
+        assert_approx_eq!(centroid.normal, Vec3::new(0.0, 0.0, -1.0));
+
+        assert!(hit(&triangle, Vec3::new(0.5, -0.01, -1.0), z).is_none());
+        assert!(hit(&triangle, Vec3::new(-0.01, 0.5, -1.0), z).is_none());
+        assert!(hit(&triangle, Vec3::new(0.51, 0.5, -1.0), z).is_none());
+    }
+
+    #[test]
+    fn rects_hit_in_the_middle_and_miss_past_an_edge() {
+        let xy = RectXY { x0: -1.0, x1: 1.0, y0: -1.0, y1: 1.0, k: 2.0, material: gray(), flip: false }.rect();
+        let xz = RectXZ { x0: -1.0, x1: 1.0, z0: -1.0, z1: 1.0, k: 2.0, material: gray(), flip: false }.rect();
+        let yz = RectYZ { y0: -1.0, y1: 1.0, z0: -1.0, z1: 1.0, k: 2.0, material: gray(), flip: false }.rect();
+        for (rect, axis) in [(xy, 2), (xz, 1), (yz, 0)] {
+            let mut direction = Vec3::zero();
+            direction.set(axis, 1.0);
+            let center = hit(&rect, Vec3::zero(), direction).unwrap();
+            assert_eq!(center.t, 2.0);
+            assert_approx_eq!(center.position, direction * 2.0);
+            assert_approx_eq!(Vec3::new(center.u, center.v, 0.0), Vec3::new(0.5, 0.5, 0.0));
+            // seen from behind the facing side
+            assert!(!center.front_face);
+
+            let (a, _) = rect.plane_axes();
+            let mut outside = Vec3::zero();
+            outside.set(a, 1.01);
+            assert!(hit(&rect, outside, direction).is_none());
+            outside.set(a, 0.99);
+            assert!(hit(&rect, outside, direction).is_some());
+        }
+    }
+
+    #[test]
+    fn sphere_tangent_inside_and_degenerate_rays() {
+        let sphere = Sphere::new(Vec3::new(0.0, 0.0, -3.0), 0.5, gray());
+        // the discriminant is zero for a ray just touching the side
+        let tangent = hit(&sphere, Vec3::new(0.5, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0)).unwrap();
+        assert_eq!(tangent.t, 3.0);
+        assert_approx_eq!(tangent.position, Vec3::new(0.5, 0.0, -3.0));
+        assert_eq!(tangent.normal.dot(Vec3::new(0.0, 0.0, -1.0)), 0.0);
+
+        // from the center the near root is behind the ray, it leaves through the far one
+        let inside = hit(&sphere, Vec3::new(0.0, 0.0, -3.0), Vec3::new(0.0, 1.0, 0.0)).unwrap();
+        assert_eq!(inside.t, 0.5);
+        assert!(!inside.front_face);
+        assert_approx_eq!(inside.normal, Vec3::new(0.0, -1.0, 0.0));
+
+        assert!(hit(&sphere, Vec3::zero(), Vec3::zero()).is_none());
+    }
+
+    #[test]
+    fn cylinder_axis_side_tangent_and_inside_rays() {
+        let cylinder = Cylinder::new(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 1.0, 2.0, gray(), true);
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        let x = Vec3::new(1.0, 0.0, 0.0);
+
+        // down the axis onto the top cap
+        let cap = hit(&cylinder, Vec3::new(0.0, 5.0, 0.0), down).unwrap();
+        assert_eq!(cap.t, 3.0);
+        assert_approx_eq!(cap.normal, Vec3::new(0.0, 1.0, 0.0));
+        let open = Cylinder::new(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 1.0, 2.0, gray(), false);
+        assert!(hit(&open, Vec3::new(0.0, 5.0, 0.0), down).is_none());
+
+        let side = hit(&cylinder, Vec3::new(-5.0, 1.0, 0.0), x).unwrap();
+        assert_eq!(side.t, 4.0);
+        assert_approx_eq!(side.normal, Vec3::new(-1.0, 0.0, 0.0));
+        assert!(hit(&cylinder, Vec3::new(-5.0, 2.01, 0.0), x).is_none());
+
+        let tangent = hit(&cylinder, Vec3::new(-5.0, 1.0, 1.0), x).unwrap();
+        assert_eq!(tangent.t, 5.0);
+        assert_approx_eq!(tangent.position, Vec3::new(0.0, 1.0, 1.0));
+
+        let inside = hit(&cylinder, Vec3::new(0.0, 1.0, 0.0), x).unwrap();
+        assert_eq!(inside.t, 1.0);
+        assert!(!inside.front_face);
+        assert_approx_eq!(inside.normal, Vec3::new(-1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn disk_center_and_just_outside_the_radius() {
+        let disk = Disk::new(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 1.0, gray());
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        let center = hit(&disk, Vec3::new(0.0, 2.0, 0.0), down).unwrap();
+        assert_eq!(center.t, 2.0);
+        assert!(center.front_face);
+        assert_eq!(center.v, 0.0);
+
+        let edge = hit(&disk, Vec3::new(0.99, 2.0, 0.0), down).unwrap();
+        assert_approx_eq!(Vec3::splat(edge.v), Vec3::splat(0.99));
+        assert!(hit(&disk, Vec3::new(1.01, 2.0, 0.0), down).is_none());
+        assert!(hit(&disk, Vec3::new(0.0, 2.0, -1.01), down).is_none());
+        // parallel to the disk
+        assert!(hit(&disk, Vec3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn cuboid_faces_have_outward_normals() {
+        let cuboid = Cuboid::new(Vec3::splat(-1.0), Vec3::splat(1.0), gray());
+        for axis in 0..3 {
+            for side in [-1.0, 1.0] {
+                let mut outward = Vec3::zero();
+                outward.set(axis, side);
+                let face = hit(&cuboid, outward * 5.0, -outward).unwrap();
+                assert_eq!(face.t, 4.0);
+                assert!(face.front_face);
+                assert_approx_eq!(face.normal, outward);
+                assert_approx_eq!(face.position, outward);
+            }
+        }
+    }
+
+    #[test]
+    fn cube_turned_90_degrees_is_hit_where_it_went() {
+        // (x, y, z) turns to (z, y, -x), so the cube ends up over z from -1 to 0
+        let cube = RotateY::new(Box::new(Cuboid::new(Vec3::zero(), Vec3::splat(1.0), gray())), 90.0);
+        let bbox = cube.bounding_box().unwrap();
+        assert_approx_eq!(bbox.min, Vec3::new(0.0, 0.0, -1.0));
+        assert_approx_eq!(bbox.max, Vec3::new(1.0, 1.0, 0.0));
+
+        // the face that was at x = 1 now faces -z
+        let front = hit(&cube, Vec3::new(0.5, 0.5, -5.0), Vec3::new(0.0, 0.0, 1.0)).unwrap();
+        assert_approx_eq!(Vec3::splat(front.t), Vec3::splat(4.0));
+        assert_approx_eq!(front.position, Vec3::new(0.5, 0.5, -1.0));
+        assert_approx_eq!(front.normal, Vec3::new(0.0, 0.0, -1.0));
+
+        let back = hit(&cube, Vec3::new(0.5, 0.5, 5.0), Vec3::new(0.0, 0.0, -1.0)).unwrap();
+        assert_approx_eq!(Vec3::splat(back.t), Vec3::splat(5.0));
+        assert_approx_eq!(back.normal, Vec3::new(0.0, 0.0, 1.0));
+        assert!(hit(&cube, Vec3::new(-0.5, 0.5, -5.0), Vec3::new(0.0, 0.0, 1.0)).is_none());
+
+        let moved = Translate::new(Box::new(cube), Vec3::new(0.0, 2.0, 0.0));
+        let above = hit(&moved, Vec3::new(0.5, 2.5, -5.0), Vec3::new(0.0, 0.0, 1.0)).unwrap();
+        assert_approx_eq!(above.position, Vec3::new(0.5, 2.5, -1.0));
+    }
+
+    #[test]
+    fn dense_media_scatter_and_thin_ones_mostly_let_light_through() {
+        let fog = |density| {
+            let albedo = Texture::Solid(Vec3::new(0.9, 0.8, 0.7));
+            ConstantMedium::new(Box::new(Sphere::new(Vec3::zero(), 1.0, gray())), density, Material::Isotropic { albedo })
+        };
+        let ray = Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0));
+        let mut random = StdRng::seed_from_u64(63);
+
+        // at a density of 100 half the rays are gone in 0.007, all of them well
+        // before the far side
+        let dense = fog(100.0);
+        for _ in 0..1000 {
+            let hit = dense.hit_test(&ray, 0.001, f32::MAX, &mut random).expect("passed through a dense medium");
+            assert!(hit.t >= 4.0 && hit.t < 4.2, "{}", hit.t);
+            let scatter = hit.material.scatter(&ray, &hit, &mut random).expect("the phase material absorbed the ray");
+            assert_approx_eq!(scatter.attenuation, Vec3::new(0.9, 0.8, 0.7));
+            assert_approx_eq!(scatter.scattered_ray.origin, hit.position);
+            assert!((scatter.scattered_ray.direction.length() - 1.0).abs() < 1e-4);
+        }
+
+        // through 2 at a density of 0.01 about exp(-0.02) of them get across
+        let thin = fog(0.01);
+        let scattered = (0..10000).filter(|_| thin.hit_test(&ray, 0.001, f32::MAX, &mut random).is_some()).count();
+        assert!(scattered > 100 && scattered < 300, "{} of 10000 scattered", scattered);
+    }
+
+    #[test]
+    fn an_unreadable_mtl_file_is_a_warning() {
+        let path = std::env::temp_dir().join(format!("kektracer-{}-mtl.obj", std::process::id()));
+        std::fs::write(&path, "mtllib missing.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl red\nf 1 2 3\n").unwrap();
+        let mut warnings = Vec::new();
+        let triangles = load_obj(&path.to_string_lossy(), gray(), &mut warnings);
+        std::fs::remove_file(&path).unwrap();
+
+        let triangles = triangles.unwrap();
+        assert_eq!(triangles.len(), 1);
+        assert!(matches!(triangles[0].material, Material::Lambertian { .. }));
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("missing.mtl"), "{}", warnings[0]);
+    }
+}
@@ -0,0 +1,133 @@
+// messages that are only shown with --verbose, the library prints at the
+// verbosity the render settings were given
+macro_rules! detail {
+    ($settings:expr, $($arg:tt)*) => {
+        if $settings.verbosity >= 2 {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+// for tests, fails with both vectors shown when they differ by eps or more in
+// any component, which defaults to 1e-5
+#[cfg(test)]
+macro_rules! assert_approx_eq {
+    ($left:expr, $right:expr) => {
+        assert_approx_eq!($left, $right, 1e-5)
+    };
+    ($left:expr, $right:expr, $eps:expr) => {{
+        let (left, right): ($crate::Vec3, $crate::Vec3) = ($left, $right);
+        assert!(left.approx_eq(right, $eps), "{} is not within {} of {}", left, $eps, right);
+    }};
+}
+
+mod geometry;
+mod material;
+mod bvh;
+mod scene;
+mod render;
+
+pub use geometry::{Mesh, Vec3};
+pub use material::{Material, Texture};
+pub use bvh::BvhBuild;
+pub use scene::{Projection, Scene, ScenePreset, default_scene};
+pub use render::{Accumulation, AdaptiveSampling, Aov, Bitmap, Checkpoint, Filter, Overlay, Region, RenderMode, RenderSettings,
+                 ToneMap, Transfer, color, draw_overlay, print_stats, render, render_into, render_to_completion, reset_stats, set_stats_enabled};
+
+fn clamped<T: PartialOrd>(x: T, min: T, max: T) -> T {
+    if x < min {
+        min
+    } else if x > max {
+        max
+    } else {
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn approx_eq_compares_every_component() {
+        let v = Vec3::new(1.0, 2.0, 3.0);
+        assert!(v.approx_eq(Vec3::new(1.0 + 1e-6, 2.0, 3.0 - 1e-6), 1e-5));
+        assert!(!v.approx_eq(Vec3::new(1.0, 2.0, 3.1), 1e-5));
+        assert!(!v.approx_eq(Vec3::new(0.9, 2.0, 3.0), 1e-5));
+        assert_approx_eq!(v, Vec3::new(1.05, 1.95, 3.0), 0.1);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not within")]
+    fn assert_approx_eq_fails_outside_eps() {
+        assert_approx_eq!(Vec3::new(1.0, 0.0, 0.0), Vec3::new(1.001, 0.0, 0.0));
+    }
+
+    #[test]
+    fn is_finite_catches_nan_and_infinity() {
+        assert!(Vec3::new(1.0, -2.0, 1e30).is_finite());
+        assert!(!Vec3::new(f32::NAN, 0.0, 0.0).is_finite());
+        assert!(!Vec3::new(0.0, f32::INFINITY, 0.0).is_finite());
+        assert!(!Vec3::new(0.0, 0.0, f32::NEG_INFINITY).is_finite());
+    }
+
+    #[test]
+    fn random_points_are_inside_the_unit_sphere() {
+        let mut random = rand::thread_rng();
+        for _ in 0..1000 {
+            assert!(Vec3::random_in_unit_sphere(&mut random).squared_length() < 1.0);
+            assert!(Vec3::random_in_unit_sphere_thread_rng().squared_length() < 1.0);
+        }
+    }
+
+    #[test]
+    fn unit_vector_and_cross() {
+        assert_approx_eq!(Vec3::new(3.0, 0.0, 4.0).unit_vector(), Vec3::new(0.6, 0.0, 0.8));
+        assert_approx_eq!(Vec3::new(1.0, 0.0, 0.0).cross(Vec3::new(0.0, 1.0, 0.0)), Vec3::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn compound_assignment_matches_the_operators() {
+        let a = Vec3::new(1.5, -2.0, 0.25);
+        let b = Vec3::new(-0.5, 4.0, 2.0);
+        let mut c = a;
+        c += b;
+        assert_eq!(c, a + b);
+        let mut c = a;
+        c -= b;
+        assert_eq!(c, a - b);
+        let mut c = a;
+        c *= b;
+        assert_eq!(c, a * b);
+        let mut c = a;
+        c *= 3.0;
+        assert_eq!(c, a * 3.0);
+        assert_eq!(a * 3.0, 3.0 * a);
+        assert_eq!(a + b, Vec3::new(1.0, 2.0, 2.25));
+        assert_eq!(-a, Vec3::zero() - a);
+
+        let (ra, rb) = (&a, &b);
+        assert_eq!(ra + rb, a + b);
+        assert_eq!(ra - rb, a - b);
+        assert_eq!(ra * rb, a * b);
+        assert_eq!(ra * 3.0, a * 3.0);
+        let mut c = a;
+        c += rb;
+        assert_eq!(c, a + b);
+    }
+
+    #[test]
+    fn reflect_and_refract_at_known_angles() {
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        assert_approx_eq!(Vec3::new(1.0, -1.0, 0.0).reflect(up), Vec3::new(1.0, 1.0, 0.0));
+        assert_approx_eq!(Vec3::new(0.0, -1.0, 0.0).reflect(up), up);
+
+        // straight in goes straight through, at 30 degrees sine is divided by the index
+        assert_approx_eq!(Vec3::new(0.0, -1.0, 0.0).refract(up, 1.0 / 1.5).unwrap(), Vec3::new(0.0, -1.0, 0.0));
+        let (sin, cos) = 30f32.to_radians().sin_cos();
+        let refracted = Vec3::new(sin, -cos, 0.0).refract(up, 1.0 / 1.5).unwrap();
+        assert_approx_eq!(Vec3::new(refracted.length(), refracted.x, 0.0), Vec3::new(1.0, sin / 1.5, 0.0));
+        // past the critical angle of about 42 degrees nothing leaves glass
+        assert!(Vec3::new(1.0, -1.0, 0.0).refract(up, 1.5).is_none());
+    }
+}
@@ -1,501 +1,826 @@
-use minifb::{Window, WindowOptions, Key, Scale};
+use minifb::{Window, WindowOptions, Key, KeyRepeat, Scale};
 use std::error::Error;
-use rand::Rng;
-use std::time::{Instant, Duration};
+use std::io::BufRead;
+use std::time::{Instant, Duration, SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread::sleep;
-use rayon::prelude::*;
+use rand::Rng;
+use raytracer::{Accumulation, AdaptiveSampling, Aov, Bitmap, BvhBuild, Checkpoint, Filter, Material, Mesh, Overlay, Projection, RenderMode,
+                Region, RenderSettings, Scene, ScenePreset, Texture, ToneMap, Transfer, Vec3, default_scene, draw_overlay, print_stats, render,
+                render_to_completion, reset_stats, set_stats_enabled};
 
-fn time<F: FnOnce()>(f: F) -> Duration {
-    let timer = Instant::now();
-    f();
-    timer.elapsed()
-}
+// how much goes to stderr: 0 with --quiet, 1 by default and 2 with --verbose,
+// errors and output that was asked for like --stats are printed regardless
+static VERBOSITY: AtomicUsize = AtomicUsize::new(1);
 
-fn clamped<T: PartialOrd>(x: T, min: T, max: T) -> T {
-    if x < min {
-        min
-    } else if x > max {
-        max
-    } else {
-        x
-    }
+fn verbosity() -> usize {
+    VERBOSITY.load(Ordering::Relaxed)
 }
 
-struct Bitmap {
-    width: usize,
-    height: usize,
-    buffer: Vec<u32>,
+fn set_verbosity(level: usize) {
+    VERBOSITY.store(level, Ordering::Relaxed);
 }
 
-impl Bitmap {
-    fn new(width: usize, height: usize) -> Bitmap {
-        Bitmap {
-            width,
-            height,
-            buffer: vec![0; width * height],
+// messages that are shown unless --quiet is given
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if verbosity() >= 1 {
+            eprintln!($($arg)*);
         }
-    }
-
-    fn width(&self) -> usize {
-        self.width
-    }
-
-    fn height(&self) -> usize {
-        self.height
-    }
+    };
+}
 
-    fn get_mut(&mut self, x: usize, y: usize) -> Option<&mut u32> {
-        if x >= self.width || y >= self.height {
-            None
-        } else {
-            Some(&mut self.buffer[(self.height - y - 1) * self.width + (x)])
+// messages that are only shown with --verbose
+macro_rules! detail {
+    ($($arg:tt)*) => {
+        if verbosity() >= 2 {
+            eprintln!($($arg)*);
         }
-    }
-
-    fn iter_mut(& mut self) -> impl Iterator<Item=(usize, usize, &mut u32)> {
-        let width = self.width;
-        let height = self.height;
-        self.buffer
-            .iter_mut()
-            .enumerate()
-            .map(move |(i, v)| {
-                let y = i / width;
-                let x = i - y * width;
-                (x, height - y - 1, v)
-            })
-    }
-
-    fn buffer(&self) -> &[u32] {
-        &self.buffer
-    }
+    };
 }
 
-#[derive(Copy, Clone)]
-struct Vec3 {
-    x: f32,
-    y: f32,
-    z: f32,
+fn time<F: FnOnce()>(f: F) -> Duration {
+    let timer = Instant::now();
+    f();
+    timer.elapsed()
 }
 
-impl Vec3 {
-    fn new(x: f32, y: f32, z: f32) -> Vec3 {
-        Vec3 { x, y, z }
-    }
-
-    fn random_in_unit_sphere() -> Vec3 {
-        let mut random = rand::thread_rng();
+// keys that make the pass in progress pointless, movement and quitting
+const INTERRUPT_KEYS: [Key; 10] = [Key::W, Key::S, Key::A, Key::D, Key::Up, Key::Down, Key::Left, Key::Right, Key::Escape, Key::Q];
+
+// the most pixels rendered without --max-pixels, including the supersampled
+// ones, about 4 GB of accumulation buffers
+const DEFAULT_MAX_PIXELS: usize = 50_000_000;
+
+// what the system could still give us, from /proc/meminfo so on linux only;
+// elsewhere this is None and only --max-pixels and the allocation itself stand
+// in the way of a size that doesn't fit, where a failed allocation is still
+// an error but a system that overcommits may only run out once it is touched
+fn available_memory() -> Option<usize> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+    let kilobytes: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+    kilobytes.checked_mul(1024)
+}
 
-        loop {
-            let x = random.gen_range(-1.0, 1.0);
-            let y = random.gen_range(-1.0, 1.0);
-            let z = random.gen_range(-1.0, 1.0);
-            let p = Vec3::new(x, y, z);
+// how much larger the window shows the image, or as large as the screen allows
+#[derive(Clone, Copy)]
+enum WindowScale {
+    Factor(usize),
+    Fit,
+}
 
-            if p.squared_length() < 1.0 {
-                return p
-            }
+impl std::str::FromStr for WindowScale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<WindowScale, String> {
+        match s {
+            "1" => Ok(WindowScale::Factor(1)),
+            "2" => Ok(WindowScale::Factor(2)),
+            "4" => Ok(WindowScale::Factor(4)),
+            "8" => Ok(WindowScale::Factor(8)),
+            "fit" => Ok(WindowScale::Fit),
+            _ => Err(format!("unknown scale: {}", s)),
         }
     }
+}
 
-    fn zero() -> Vec3 {
-        Vec3::new(0.00, 0.0, 0.0)
-    }
+struct Options {
+    output: Option<String>,
+    hdr: Option<String>,
+    aov: Vec<Aov>,
+    overlay: Vec<Overlay>,
+    headless: bool,
+    stdin_scenes: bool,
+    stats: bool,
+    quiet: bool,
+    verbose: bool,
+    packets: bool,
+    bvh: BvhBuild,
+    scale: WindowScale,
+    smooth: bool,
+    scene: Option<String>,
+    preset: Option<ScenePreset>,
+    checkpoint: Option<String>,
+    checkpoint_interval: u64,
+    resume: bool,
+    frames: Option<usize>,
+    orbit: bool,
+    vary_seed: bool,
+    mesh: Option<String>,
+    width: Option<usize>,
+    height: Option<usize>,
+    samples: Option<usize>,
+    min_samples: Option<usize>,
+    max_samples: Option<usize>,
+    tolerance: Option<f32>,
+    bounces: Option<usize>,
+    denoise: bool,
+    tone_map: Option<ToneMap>,
+    transfer: Option<Transfer>,
+    stratified: bool,
+    mode: Option<RenderMode>,
+    ao_radius: Option<f32>,
+    filter: Option<Filter>,
+    firefly_clamp: Option<f32>,
+    ssaa: Option<usize>,
+    time_budget_ms: Option<u64>,
+    ray_epsilon: Option<f32>,
+    max_pixels: Option<usize>,
+    fast_aa: bool,
+    region: Option<Region>,
+    alpha: bool,
+    seed: Option<u64>,
+    tile_size: Option<usize>,
+    gamma: Option<f32>,
+    env_rotation: Option<f32>,
+    exposure: Option<f32>,
+}
 
-    fn add(self, other: Vec3) -> Vec3 {
-        Vec3 {
-            x: self.x + other.x,
-            y: self.y + other.y,
-            z: self.z + other.z,
+impl Options {
+    fn from_args() -> Result<Options, Box<dyn Error>> {
+        fn value(args: &mut impl Iterator<Item=String>, name: &str) -> Result<String, Box<dyn Error>> {
+            args.next().ok_or_else(|| format!("{} requires a value", name).into())
         }
-    }
 
-    fn subtract(self, other: Vec3) -> Vec3 {
-        Vec3 {
-            x: self.x - other.x,
-            y: self.y - other.y,
-            z: self.z - other.z,
+        fn parsed<T: std::str::FromStr>(args: &mut impl Iterator<Item=String>, name: &str) -> Result<T, Box<dyn Error>> {
+            let v = value(args, name)?;
+            v.parse().map_err(|_| format!("invalid value for {}: {}", name, v).into())
         }
-    }
 
-    fn negate(self) -> Vec3 {
-        Vec3 {
-            x: -self.x,
-            y: -self.y,
-            z: -self.z,
+        let mut options = Options {
+            output: None,
+            hdr: None,
+            aov: Vec::new(),
+            overlay: Vec::new(),
+            headless: false,
+            stdin_scenes: false,
+            stats: false,
+            quiet: false,
+            verbose: false,
+            packets: false,
+            bvh: BvhBuild::default(),
+            scale: WindowScale::Factor(2),
+            smooth: false,
+            scene: None,
+            preset: None,
+            checkpoint: None,
+            checkpoint_interval: 60,
+            resume: false,
+            frames: None,
+            orbit: false,
+            vary_seed: false,
+            mesh: None,
+            width: None,
+            height: None,
+            samples: None,
+            min_samples: None,
+            max_samples: None,
+            tolerance: None,
+            bounces: None,
+            denoise: false,
+            tone_map: None,
+            transfer: None,
+            stratified: false,
+            mode: None,
+            ao_radius: None,
+            filter: None,
+            firefly_clamp: None,
+            ssaa: None,
+            time_budget_ms: None,
+            ray_epsilon: None,
+            max_pixels: None,
+            fast_aa: false,
+            region: None,
+            alpha: false,
+            seed: None,
+            tile_size: None,
+            gamma: None,
+            env_rotation: None,
+            exposure: None,
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--output" => options.output = Some(value(&mut args, &arg)?),
+                "--hdr" => options.hdr = Some(value(&mut args, &arg)?),
+                "--overlay" => {
+                    options.overlay = value(&mut args, &arg)?.split(',').map(|s| s.trim().parse()).collect::<Result<_, String>>()?
+                }
+                "--aov" => {
+                    options.aov = value(&mut args, &arg)?.split(',').map(|s| s.trim().parse()).collect::<Result<_, String>>()?
+                }
+                "--headless" => options.headless = true,
+                "--stdin-scenes" => options.stdin_scenes = true,
+                "--stats" => options.stats = true,
+                "--quiet" => options.quiet = true,
+                "--verbose" => options.verbose = true,
+                "--packets" => options.packets = true,
+                "--bvh" => options.bvh = parsed(&mut args, &arg)?,
+                "--scale" => options.scale = parsed(&mut args, &arg)?,
+                "--smooth" => options.smooth = true,
+                "--scene" => options.scene = Some(value(&mut args, &arg)?),
+                "--scene-preset" => options.preset = Some(parsed(&mut args, &arg)?),
+                "--checkpoint" => options.checkpoint = Some(value(&mut args, &arg)?),
+                "--checkpoint-interval" => options.checkpoint_interval = parsed(&mut args, &arg)?,
+                "--resume" => options.resume = true,
+                "--frames" => options.frames = Some(parsed(&mut args, &arg)?),
+                "--orbit" => options.orbit = true,
+                "--vary-seed" => options.vary_seed = true,
+                "--mesh" => options.mesh = Some(value(&mut args, &arg)?),
+                "--width" => options.width = Some(parsed(&mut args, &arg)?),
+                "--height" => options.height = Some(parsed(&mut args, &arg)?),
+                "--samples" => options.samples = Some(parsed(&mut args, &arg)?),
+                "--min-samples" => options.min_samples = Some(parsed(&mut args, &arg)?),
+                "--max-samples" => options.max_samples = Some(parsed(&mut args, &arg)?),
+                "--tolerance" => options.tolerance = Some(parsed(&mut args, &arg)?),
+                "--bounces" => options.bounces = Some(parsed(&mut args, &arg)?),
+                "--denoise" => options.denoise = true,
+                "--tonemap" => options.tone_map = Some(parsed(&mut args, &arg)?),
+                "--transfer" => options.transfer = Some(parsed(&mut args, &arg)?),
+                "--stratified" => options.stratified = true,
+                "--mode" => options.mode = Some(parsed(&mut args, &arg)?),
+                "--ao-radius" => options.ao_radius = Some(parsed(&mut args, &arg)?),
+                "--filter" => options.filter = Some(parsed(&mut args, &arg)?),
+                "--firefly-clamp" => options.firefly_clamp = Some(parsed(&mut args, &arg)?),
+                "--ssaa" => options.ssaa = Some(parsed(&mut args, &arg)?),
+                "--time-budget-ms" => options.time_budget_ms = Some(parsed(&mut args, &arg)?),
+                "--ray-epsilon" => options.ray_epsilon = Some(parsed(&mut args, &arg)?),
+                "--alpha" => options.alpha = true,
+                "--fast-aa" => options.fast_aa = true,
+                "--region" => options.region = Some(parsed(&mut args, &arg)?),
+                "--max-pixels" => options.max_pixels = Some(parsed(&mut args, &arg)?),
+                "--seed" => options.seed = Some(parsed(&mut args, &arg)?),
+                "--tile-size" => options.tile_size = Some(parsed(&mut args, &arg)?),
+                "--gamma" => options.gamma = Some(parsed(&mut args, &arg)?),
+                "--env-rotation" => options.env_rotation = Some(parsed(&mut args, &arg)?),
+                "--exposure" => options.exposure = Some(parsed(&mut args, &arg)?),
+                _ => return Err(format!("unknown argument: {}", arg).into()),
+            }
         }
-    }
 
-    fn multiply(self, other: Vec3) -> Vec3 {
-        Vec3 {
-            x: self.x * other.x,
-            y: self.y * other.y,
-            z: self.z * other.z,
+        if options.quiet && options.verbose {
+            return Err("--quiet and --verbose can't be used together".into());
         }
-    }
-
-    fn multiply_scalar(self, scalar: f32) -> Vec3 {
-        Vec3 {
-            x: self.x * scalar,
-            y: self.y * scalar,
-            z: self.z * scalar,
+        if options.scene.is_some() && options.preset.is_some() {
+            return Err("--scene and --scene-preset can't be used together".into());
         }
-    }
-
-    fn div(self, other: Vec3) -> Vec3 {
-        Vec3 {
-            x: self.x / other.x,
-            y: self.y / other.y,
-            z: self.z / other.z,
+        if options.checkpoint.is_some() && options.output.is_none() && options.hdr.is_none() {
+            return Err("--checkpoint requires --output or --hdr".into());
         }
-    }
-
-    fn div_scalar(self, scalar: f32) -> Vec3 {
-        Vec3 {
-            x: self.x / scalar,
-            y: self.y / scalar,
-            z: self.z / scalar,
+        if options.resume && options.checkpoint.is_none() {
+            return Err("--resume requires --checkpoint".into());
         }
-    }
-
-    fn dot(self, other: Vec3) -> f32 {
-        self.x * other.x + self.y * other.y + self.z * other.z
-    }
-
-    fn cross(self, other: Vec3) -> Vec3 {
-        Vec3 {
-            x: self.y * other.z - self.z * other.y,
-            y: self.z * other.x - self.x * other.z,
-            z: self.x * other.y - self.y * other.x,
+        if options.frames.is_some() && options.output.is_none() {
+            return Err("--frames requires --output, the directory to write the frames to".into());
+        }
+        if options.frames.is_some() && (options.hdr.is_some() || options.checkpoint.is_some()) {
+            return Err("--frames can't be used with --hdr or --checkpoint".into());
+        }
+        if options.frames == Some(0) {
+            return Err("--frames must be at least 1".into());
+        }
+        if (options.orbit || options.vary_seed) && options.frames.is_none() {
+            return Err("--orbit and --vary-seed require --frames".into());
+        }
+        // the passes are named after the file the combined image goes to
+        if !options.aov.is_empty() && options.aov_base().is_none() {
+            return Err("--aov requires --hdr or an --output file".into());
+        }
+        if !options.aov.is_empty() && options.frames.is_some() {
+            return Err("--aov can't be used with --frames".into());
+        }
+        if options.headless && options.output.is_none() && options.hdr.is_none() {
+            return Err("--headless requires --output or --hdr".into());
+        }
+        if options.stdin_scenes && (options.headless || options.frames.is_some()) {
+            return Err("--stdin-scenes needs the window, it can't be used with --headless or --frames".into());
+        }
+        if options.width == Some(0) {
+            return Err("--width must be at least 1".into());
+        }
+        if options.height == Some(0) {
+            return Err("--height must be at least 1".into());
+        }
+        if options.samples == Some(0) {
+            return Err("--samples must be at least 1".into());
+        }
+        if options.min_samples == Some(0) {
+            return Err("--min-samples must be at least 1".into());
+        }
+        if options.tolerance.is_some_and(|t| t <= 0.0) {
+            return Err("--tolerance must be positive".into());
+        }
+        if options.tile_size == Some(0) {
+            return Err("--tile-size must be at least 1".into());
+        }
+        if options.gamma.is_some_and(|g| g <= 0.0) {
+            return Err("--gamma must be positive".into());
+        }
+        if options.gamma.is_some() && options.transfer == Some(Transfer::Srgb) {
+            return Err("--gamma only applies to --transfer gamma, srgb has its own curve".into());
+        }
+        if options.env_rotation.is_some_and(|r| !r.is_finite()) {
+            return Err("--env-rotation must be a number of degrees".into());
+        }
+        if options.exposure.is_some_and(|e| !e.is_finite()) {
+            return Err("--exposure must be a number of stops".into());
+        }
+        if options.firefly_clamp.is_some_and(|l| l <= 0.0) {
+            return Err("--firefly-clamp must be positive".into());
+        }
+        if options.ssaa == Some(0) {
+            return Err("--ssaa must be at least 1".into());
+        }
+        if options.time_budget_ms == Some(0) {
+            return Err("--time-budget-ms must be at least 1".into());
+        }
+        if options.max_pixels == Some(0) {
+            return Err("--max-pixels must be at least 1".into());
+        }
+        if options.ao_radius.is_some_and(|r| r <= 0.0 || r.is_nan()) {
+            return Err("--ao-radius must be positive".into());
+        }
+        if options.ray_epsilon.is_some_and(|e| e < 0.0) {
+            return Err("--ray-epsilon can't be negative".into());
+        }
+        // only png keeps the alpha channel
+        let png_output = options.output.as_ref().is_some_and(|o| o.ends_with(".png")) || options.frames.is_some();
+        if options.alpha && !png_output {
+            return Err("--alpha requires a .png --output or --frames".into());
         }
-    }
-
-    fn length(self) -> f32 {
-        (self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
-    }
-
-    fn squared_length(self) -> f32 {
-        self.x * self.x + self.y * self.y + self.z * self.z
-    }
-
-    fn unit_vector(self) -> Vec3 {
-        self.div_scalar(self.length())
-    }
-
-    fn lerp(from: Vec3, to: Vec3, v: f32) -> Vec3 {
-        let v = v.min(1.0).max(0.0);
-        from * (1.0 - v) + to * v
-    }
-}
-
-impl std::ops::Add<Self> for Vec3 {
-    type Output = Vec3;
-
-    fn add(self, rhs: Self) -> Self::Output {
-        self.add(rhs)
-    }
-}
-
-impl std::ops::Neg for Vec3 {
-    type Output = Vec3;
-
-    fn neg(self) -> Self::Output {
-        self.negate()
-    }
-}
-
-impl std::ops::Sub<Self> for Vec3 {
-    type Output = Vec3;
-
-    fn sub(self, rhs: Self) -> Self::Output {
-        self.subtract(rhs)
-    }
-}
-
-impl std::ops::Mul<Self> for Vec3 {
-    type Output = Vec3;
-
-    fn mul(self, rhs: Self) -> Self::Output {
-        self.multiply(rhs)
-    }
-}
-
-impl std::ops::Mul<f32> for Vec3 {
-    type Output = Vec3;
-
-    fn mul(self, rhs: f32) -> Self::Output {
-        self.multiply_scalar(rhs)
-    }
-}
-
-impl std::ops::Mul<Vec3> for f32 {
-    type Output = Vec3;
 
-    fn mul(self, rhs: Vec3) -> Self::Output {
-        rhs.multiply_scalar(self)
+        Ok(options)
     }
-}
 
-impl std::ops::Div<Self> for Vec3 {
-    type Output = Vec3;
+    // a hash of what the scene was loaded from, which a checkpoint is resumed
+    // with; files the scene itself refers to, like its meshes, aren't included
+    fn scene_hash(&self) -> Result<u64, Box<dyn Error>> {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        match (&self.scene, self.preset) {
+            (Some(path), _) => std::fs::read(path)?.hash(&mut hasher),
+            (None, Some(preset)) => (preset as u8, self.seed.unwrap_or(0)).hash(&mut hasher),
+            (None, None) => "default".hash(&mut hasher),
+        }
+        if let Some(path) = &self.mesh {
+            std::fs::read(path)?.hash(&mut hasher);
+        }
+        Ok(hasher.finish())
+    }
+
+    // render.png gives render.direct.exr and so on
+    fn aov_base(&self) -> Option<String> {
+        let path = self.hdr.as_ref().or(self.output.as_ref()).filter(|path| path.as_str() != "-")?;
+        let path = std::path::Path::new(path);
+        Some(path.with_extension("").to_string_lossy().into_owned())
+    }
+
+    // settings given on the command line take precedence over the scene's
+    fn settings(&self, scene_settings: RenderSettings) -> Result<RenderSettings, Box<dyn Error>> {
+        // any of the adaptive options turns adaptive sampling on, the rest
+        // come from the scene or default to sampling up to the usual count
+        let samples_per_pixel = self.samples.unwrap_or(scene_settings.samples_per_pixel);
+        let adaptive_given = self.min_samples.is_some() || self.max_samples.is_some() || self.tolerance.is_some();
+        let adaptive = match scene_settings.adaptive {
+            Some(adaptive) => Some(AdaptiveSampling {
+                min_samples: self.min_samples.unwrap_or(adaptive.min_samples),
+                max_samples: self.max_samples.unwrap_or(adaptive.max_samples),
+                tolerance: self.tolerance.unwrap_or(adaptive.tolerance),
+            }),
+            None if adaptive_given => Some(AdaptiveSampling {
+                min_samples: self.min_samples.unwrap_or(16),
+                max_samples: self.max_samples.unwrap_or(samples_per_pixel),
+                tolerance: self.tolerance.unwrap_or(0.01),
+            }),
+            None => None,
+        };
+        if samples_per_pixel == 0 {
+            return Err("samples_per_pixel must be at least 1".into());
+        }
+        let tile_size = self.tile_size.unwrap_or(scene_settings.tile_size);
+        if tile_size == 0 {
+            return Err("tile_size must be at least 1".into());
+        }
+        let ssaa = self.ssaa.unwrap_or(scene_settings.ssaa);
+        if ssaa == 0 {
+            return Err("ssaa must be at least 1".into());
+        }
+        if let Some(adaptive) = &adaptive {
+            if adaptive.min_samples == 0 || adaptive.min_samples > adaptive.max_samples {
+                return Err("the minimum sample count must be between 1 and the maximum".into());
+            }
+        }
 
-    fn div(self, rhs: Self) -> Self::Output {
-        self.div(rhs)
+        Ok(RenderSettings {
+            samples_per_pixel,
+            max_bounces: self.bounces.unwrap_or(scene_settings.max_bounces),
+            seed: self.seed.or(scene_settings.seed),
+            tile_size,
+            gamma: self.gamma.unwrap_or(scene_settings.gamma),
+            env_rotation: self.env_rotation.unwrap_or(scene_settings.env_rotation),
+            exposure: self.exposure.unwrap_or(scene_settings.exposure),
+            environment: scene_settings.environment,
+            adaptive,
+            denoise: self.denoise || scene_settings.denoise,
+            tone_map: self.tone_map.unwrap_or(scene_settings.tone_map),
+            transfer: self.transfer.unwrap_or(scene_settings.transfer),
+            stratified: self.stratified || scene_settings.stratified,
+            mode: self.mode.unwrap_or(scene_settings.mode),
+            ao_radius: self.ao_radius.unwrap_or(scene_settings.ao_radius),
+            filter: self.filter.unwrap_or(scene_settings.filter),
+            firefly_clamp: self.firefly_clamp.or(scene_settings.firefly_clamp),
+            ssaa,
+            time_budget_ms: self.time_budget_ms.or(scene_settings.time_budget_ms),
+            ray_epsilon: self.ray_epsilon.unwrap_or(scene_settings.ray_epsilon),
+            alpha: self.alpha || scene_settings.alpha,
+            fast_aa: self.fast_aa || scene_settings.fast_aa,
+            region: self.region.or(scene_settings.region),
+            russian_roulette: scene_settings.russian_roulette,
+            verbosity: verbosity(),
+        })
     }
 }
 
-impl std::ops::Div<f32> for Vec3 {
-    type Output = Vec3;
-
-    fn div(self, rhs: f32) -> Self::Output {
-        self.div_scalar(rhs)
-    }
-}
+// saves on a separate thread so encoding doesn't stall the window loop
+fn save_screenshot(bitmap: &Bitmap) {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    let path = format!("screenshot-{}.png", timestamp);
+    let snapshot = bitmap.clone();
 
-struct Ray {
-    origin: Vec3,
-    direction: Vec3,
+    std::thread::spawn(move || {
+        match snapshot.save_png(&path) {
+            Ok(()) => status!("Saved {}", path),
+            Err(e) => eprintln!("Failed to save {}: {}", path, e),
+        }
+    });
 }
 
-impl Ray {
-    fn new(origin: Vec3, direction: Vec3) -> Ray {
-        Ray { origin, direction }
-    }
-
-    fn origin(&self) -> Vec3 {
-        self.origin
-    }
-
-    fn direction(&self) -> Vec3 {
-        self.direction
+fn main() -> Result<(), Box<dyn Error>> {
+    let options = Options::from_args()?;
+    let stats = options.stats;
+    set_stats_enabled(stats);
+    set_verbosity(if options.quiet { 0 } else if options.verbose { 2 } else { 1 });
+
+    // the random preset is laid out the same every time unless a seed is given
+    let mut scene = match (&options.scene, options.preset) {
+        (Some(path), _) => Scene::from_file(path)?,
+        (None, Some(preset)) => preset.scene(options.seed.unwrap_or(0)),
+        (None, None) => default_scene(),
+    };
+
+    let width = options.width.unwrap_or(400);
+    let height = options.height.unwrap_or(match scene.camera.projection {
+        Projection::Equirectangular => width / 2,
+        _ => 300,
+    });
+    if let Some(path) = &options.mesh {
+        scene.meshes.push(Mesh {
+            path: path.clone(),
+            material: Material::Lambertian { albedo: Texture::Solid(Vec3::splat(0.7)) },
+            position: Vec3::zero(),
+            size: Mesh::default_size(),
+        });
     }
-
-    fn point_at_parameter(&self, t: f32) -> Vec3 {
-        self.origin + self.direction * t
+    let mut settings = options.settings(scene.settings.clone())?;
+    if let Some(region) = settings.region.filter(|region| !region.fits(width, height)) {
+        return Err(format!("the region {} doesn't fit in the {}x{} image, it needs x0 < x1 <= {} and y0 < y1 <= {}",
+                           region, width, height, width, height).into());
+    }
+
+    // a typo in the size would otherwise only show once the allocation fails,
+    // or the system runs out of memory touching it
+    let max_pixels = options.max_pixels.unwrap_or(DEFAULT_MAX_PIXELS);
+    let render_pixels = width.checked_mul(height).and_then(|p| p.checked_mul(settings.ssaa * settings.ssaa));
+    let Some(render_pixels) = render_pixels else {
+        return Err(format!("{}x{} with ssaa {} is too large", width, height, settings.ssaa).into());
+    };
+    if render_pixels > max_pixels {
+        return Err(format!("{}x{} with ssaa {} renders more than {} pixels, raise --max-pixels to allow it",
+                           width, height, settings.ssaa, max_pixels).into());
+    }
+    let needed = Accumulation::memory_needed(width * settings.ssaa, height * settings.ssaa).unwrap_or(usize::MAX);
+    if let Some(available) = available_memory() {
+        if needed > available {
+            return Err(format!("{}x{} with ssaa {} needs about {} MB, only {} MB are available",
+                               width, height, settings.ssaa, needed >> 20, available >> 20).into());
+        }
     }
-}
-
-struct MaterialScatter {
-    attenuation: Vec3,
-    scattered_ray: Ray
-}
-
-#[derive(Clone, Copy)]
-enum Material {
-    Diffuse { albedo: Vec3 },
-    Metal { albedo: Vec3, fuzz: f32 }
-}
-
-impl Material {
-    fn scatter(&self, ray: &Ray, hit: &Hit) -> Option<MaterialScatter> {
-        match self {
-            Material::Diffuse { albedo } => {
-                let target = hit.position + hit.normal + Vec3::random_in_unit_sphere();
-                Some(MaterialScatter {
-                    attenuation: *albedo,
-                    scattered_ray: Ray::new(hit.position, target - hit.position)
-                })
+    let aspect_ratio = width as f32 / height as f32;
+    let mut camera_settings = scene.camera;
+    let mut camera = camera_settings.camera(aspect_ratio);
+    let keyframes = std::mem::take(&mut scene.keyframes);
+    if options.frames.is_some() && !options.orbit && keyframes.is_empty() {
+        return Err("--frames requires --orbit or keyframes in the scene".into());
+    }
+    let mut world = scene.world(options.packets, options.bvh)?;
+    for warning in &world.warnings {
+        status!("{}", warning);
+    }
+
+    let seed = match settings.seed {
+        Some(seed) => seed.to_string(),
+        None => "random".to_string(),
+    };
+    let samples = match &settings.adaptive {
+        _ if settings.fast_aa => "1 sample per pixel with fast anti-aliasing".to_string(),
+        Some(adaptive) => format!("{} to {} samples per pixel (tolerance {})",
+                                  adaptive.min_samples, adaptive.max_samples, adaptive.tolerance),
+        None => format!("{} samples per pixel", settings.samples_per_pixel),
+    };
+    status!("{}x{}, {}, {} bounces, seed {}", width, height, samples, settings.max_bounces, seed);
+    detail!("Exposure {} EV, tone map {:?}, transfer {:?}, gamma {}, filter {:?}, mode {:?}, tile size {}, stratified {}, denoise {}, ssaa {}",
+            settings.exposure, settings.tone_map, settings.transfer, settings.gamma, settings.filter, settings.mode, settings.tile_size, settings.stratified, settings.denoise,
+            settings.ssaa);
+    detail!("{}", world.summary());
+
+    let checkpoint = match &options.checkpoint {
+        Some(path) => Some(Checkpoint {
+            path: path.clone(),
+            interval: Duration::from_secs(options.checkpoint_interval),
+            scene: options.scene_hash()?,
+        }),
+        None => None,
+    };
+    // supersampling renders more pixels than are shown or saved
+    let (render_width, render_height) = (width * settings.ssaa, height * settings.ssaa);
+    let mut accumulation = match &checkpoint {
+        Some(checkpoint) if options.resume => {
+            let accumulation = Accumulation::load_checkpoint(checkpoint, &settings)?;
+            if accumulation.width() != render_width || accumulation.height() != render_height {
+                return Err(format!("{} is {}x{}, not {}x{}", checkpoint.path, accumulation.width(), accumulation.height(),
+                                   render_width, render_height).into());
             }
-            Material::Metal { albedo, fuzz } => {
-                fn reflect(v: Vec3, normal: Vec3) -> Vec3 {
-                    v - 2.0 * v.dot(normal) * normal
-                }
-
-                let reflected = reflect(ray.direction.unit_vector(), hit.normal);
-                let scattered_ray = Ray::new(hit.position, reflected + clamped(*fuzz, 0.0, 1.0) * Vec3::random_in_unit_sphere());
-                if scattered_ray.direction.dot(hit.normal) > 0.0 {
-                    Some(MaterialScatter{
-                        attenuation: *albedo,
-                        scattered_ray
-                    })
-                } else {
-                    None
-                }
+            status!("Resuming {} at {} samples per pixel", checkpoint.path, accumulation.samples());
+            accumulation
+        }
+        _ => Accumulation::try_new(render_width, render_height)?,
+    };
+    let mut bitmap = Bitmap::try_new(width, height)?;
+
+    // an animation goes frame by frame into the output directory, every frame
+    // with the same seed unless asked otherwise so the noise stays in place
+    if let (Some(frames), Some(directory)) = (options.frames, &options.output) {
+        std::fs::create_dir_all(directory)?;
+        let seed = settings.seed.unwrap_or_else(|| rand::thread_rng().gen());
+        for frame in 0..frames {
+            let frame_camera = if options.orbit {
+                camera_settings.orbited(360.0 * frame as f32 / frames as f32)
+            } else {
+                camera_settings.along(&keyframes, frame as f32 / (frames - 1).max(1) as f32)
+            };
+            let frame_settings = RenderSettings {
+                seed: Some(if options.vary_seed { seed.wrapping_add(frame as u64) } else { seed }),
+                ..settings.clone()
+            };
+
+            let frame_camera = frame_camera.camera(aspect_ratio);
+            let mut accumulation = Accumulation::try_new(render_width, render_height)?;
+            let mut result = Ok(());
+            let rendertime = time(|| {
+                result = render_to_completion(&mut accumulation, &world, &frame_camera, &frame_settings, None)
+            });
+            result?;
+            accumulation.resolve(&mut bitmap, &frame_settings);
+            draw_overlay(&mut bitmap, &frame_camera, &world, &options.overlay);
+
+            let path = std::path::Path::new(directory).join(format!("frame_{:04}.png", frame + 1));
+            let path = path.to_string_lossy();
+            if frame_settings.alpha {
+                bitmap.save_png_with_alpha(&path)?;
+            } else {
+                bitmap.save_png(&path)?;
             }
+            status!("Saved {} ({} ms)", path, rendertime.as_millis());
         }
+        return Ok(());
     }
-}
 
-struct Hit {
-    t: f32,
-    position: Vec3,
-    normal: Vec3,
-}
-
-impl Hit {
-    fn new(t: f32, position: Vec3, normal: Vec3) -> Hit {
-        Hit { t, position, normal }
+    // a render for a file is completed up front, the window then only displays it
+    if options.output.is_some() || options.hdr.is_some() {
+        let mut result = Ok(());
+        let rendertime = time(|| { result = render_to_completion(&mut accumulation, &world, &camera, &settings, checkpoint.as_ref()) });
+        result?;
+        status!("Render completed ({} ms, {:.1} samples per pixel)", rendertime.as_millis(), accumulation.average_samples());
+        if stats {
+            print_stats(rendertime);
+        }
+        accumulation.resolve(&mut bitmap, &settings);
+        draw_overlay(&mut bitmap, &camera, &world, &options.overlay);
     }
-}
 
-struct Sphere {
-    center: Vec3,
-    radius: f32,
-    material: Material
-}
+    if let Some(path) = &options.hdr {
+        accumulation.save_hdr(path, &settings)?;
+        status!("Saved {}", path);
+    }
 
-impl Sphere {
-    fn new(center: Vec3, radius: f32, material: Material) -> Sphere {
-        Sphere { center, radius, material }
+    if let Some(base) = options.aov_base() {
+        for &aov in &options.aov {
+            let path = format!("{}.{}.exr", base, aov.name());
+            accumulation.save_aov(aov, &path, &settings)?;
+            status!("Saved {}", path);
+        }
     }
 
-    fn hit_test(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<Hit> {
-        let oc = ray.origin() - self.center;
-        let a = ray.direction().dot(ray.direction());
-        let b = oc.dot(ray.direction());
-        let c = oc.dot(oc) - self.radius * self.radius;
-        let discriminant = b * b - a * c;
-
-        if discriminant > 0.0 {
-            let temp = (-b - (b * b - a * c).sqrt()) / a;
-            if temp < t_max && temp > t_min {
-                let point = ray.point_at_parameter(temp);
-                return Some(Hit::new(temp, point, (point - self.center) / self.radius));
+    if let Some(output) = &options.output {
+        match output.as_str() {
+            "-" => bitmap.write_ppm(&mut std::io::stdout().lock())?,
+            path if path.ends_with(".ppm") => {
+                bitmap.write_ppm(&mut std::io::BufWriter::new(std::fs::File::create(path)?))?;
+                status!("Saved {}", path);
+            }
+            path if settings.alpha => {
+                bitmap.save_png_with_alpha(path)?;
+                status!("Saved {}", path);
             }
-            let temp = (-b + (b * b - a * c).sqrt()) / a;
-            if temp < t_max && temp > t_min {
-                let point = ray.point_at_parameter(temp);
-                return Some(Hit::new(temp, point, (point - self.center) / self.radius));
+            path => {
+                bitmap.save_png(path)?;
+                status!("Saved {}", path);
             }
         }
-
-        None
     }
-}
-
-struct World {
-    spheres: Vec<Sphere>,
-}
-
-impl World {
-    fn new(spheres: Vec<Sphere>) -> World {
-        World { spheres }
-    }
-
-    fn hit_test(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(Hit, Material)> {
-        let mut closest_t = t_max;
-        let mut result = None;
 
-        self.spheres.iter().for_each(|s| {
-            if let Some(hit) = s.hit_test(ray, t_min, closest_t) {
-                closest_t = hit.t;
-                result = Some((hit, s.material));
+    // minifb needs an X server on linux, without one a render to a file is all
+    // there is to do
+    let no_display = cfg!(target_os = "linux") && std::env::var_os("DISPLAY").is_none();
+    if options.headless || (no_display && (options.output.is_some() || options.hdr.is_some())) {
+        return Ok(());
+    }
+
+    // every line on stdin is a whole scene as json, read on its own thread so
+    // waiting for the next one doesn't block the window
+    let scene_updates = options.stdin_scenes.then(|| {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                match line {
+                    Ok(line) if line.trim().is_empty() => {}
+                    Ok(line) => if sender.send(line).is_err() { break },
+                    Err(e) => {
+                        eprintln!("Failed to read stdin: {}", e);
+                        break;
+                    }
+                }
             }
         });
-
-        result
-    }
-}
-
-struct Camera {
-    origin: Vec3,
-    lower_left_corner: Vec3,
-    horizontal: Vec3,
-    vertical: Vec3,
-}
-
-impl Camera {
-    fn new(origin: Vec3, look_at: Vec3, up: Vec3, vertical_fov: f32, aspect_ratio: f32) -> Camera {
-        let half_height = (vertical_fov.to_radians() / 2.0).tan();
-        let half_width = aspect_ratio * half_height;
-
-        // create orthonormal basis
-        let w = (origin - look_at).unit_vector();
-        let u = up.cross(w).unit_vector();
-        let v = w.cross(u);
-
-        Camera {
-            lower_left_corner: origin - half_width * u - half_height * v - w,
-            horizontal: 2.0 * half_width * u,
-            vertical: 2.0 * half_height * v,
-            origin,
+        receiver
+    });
+    let (packets, bvh) = (options.packets, options.bvh);
+    let overlay = options.overlay.clone();
+    let saved = options.output.is_some() || options.hdr.is_some();
+
+    // smoothing does the scaling itself, the window then shows the result as is
+    let smooth_factor = match options.scale {
+        WindowScale::Factor(factor) if options.smooth && factor > 1 => factor,
+        _ => 1,
+    };
+    let scale = match options.scale {
+        _ if smooth_factor > 1 => Scale::X1,
+        WindowScale::Factor(1) => Scale::X1,
+        WindowScale::Factor(2) => Scale::X2,
+        WindowScale::Factor(4) => Scale::X4,
+        WindowScale::Factor(_) => Scale::X8,
+        WindowScale::Fit => Scale::FitScreen,
+    };
+    let show = |window: &mut Window, bitmap: &Bitmap| {
+        if smooth_factor > 1 {
+            window.update_with_buffer(bitmap.upscaled(smooth_factor).buffer())
+        } else {
+            window.update_with_buffer(bitmap.buffer())
         }
-    }
-
-    fn ray(&self, u: f32, v: f32) -> Ray {
-        Ray::new(self.origin, self.lower_left_corner + u * self.horizontal + v * self.vertical - self.origin)
-    }
-}
-
-fn color(ray: &Ray, world: &World, bounces: usize) -> Vec3 {
-    if let Some((hit, material)) = world.hit_test(ray, 0.001, 1000.0) {
-        if bounces == 0 {
-            return Vec3::zero();
+    };
+
+    let options = WindowOptions {
+        scale,
+        ..WindowOptions::default()
+    };
+    // a display that can't be opened isn't worth an error dump, the render is
+    // done if it went to a file
+    let mut window = match Window::new("Raytracer", bitmap.width() * smooth_factor, bitmap.height() * smooth_factor, options) {
+        Ok(window) => window,
+        Err(e) if saved => {
+            status!("No display available, the render was saved without showing it");
+            detail!("Opening the window failed: {}", e);
+            return Ok(());
         }
+        Err(e) => {
+            eprintln!("No display available; re-run with --output for headless rendering");
+            detail!("Opening the window failed: {}", e);
+            std::process::exit(1);
+        }
+    };
+    show(&mut window, &bitmap)?;
 
-        return if let Some(scatter) = material.scatter(ray, &hit) {
-            scatter.attenuation * color(&scatter.scattered_ray, world, bounces - 1)
-        } else {
-            Vec3::zero()
+    let event_poll_frequency = 30.0;
+    let millis_per_frame = (1.0 / event_poll_frequency * 1000.0) as i64;
+    let mut event_poll_start = Instant::now();
+    let mut render_start = Instant::now();
+    let mut last_frame = Instant::now();
+    let mut paused = false;
+    // set once the time budget ran out, until the camera moves again
+    let budget = settings.time_budget_ms.map(Duration::from_millis);
+    let mut budget_spent = false;
+    let mut title = String::new();
+    while window.is_open() && !window.is_key_down(Key::Escape) && !window.is_key_down(Key::Q) {
+        if window.is_key_pressed(Key::Space, KeyRepeat::No) {
+            paused = !paused;
         }
-    }
 
-    Vec3::lerp(Vec3::new(1.0, 1.0, 1.0), Vec3::new(0.5, 0.7, 1.0), (ray.direction.unit_vector().y + 1.0) * 0.5)
-}
+        if window.is_key_pressed(Key::P, KeyRepeat::No) || window.is_key_pressed(Key::F12, KeyRepeat::No) {
+            save_screenshot(&bitmap);
+        }
 
-fn render(bitmap: &mut Bitmap) {
-    fn apply_gamma_2_correction(c: Vec3) -> Vec3 {
-        Vec3::new(c.x.sqrt(), c.y.sqrt(), c.z.sqrt())
-    }
+        // switching the denoiser only changes how the samples are shown
+        if window.is_key_pressed(Key::N, KeyRepeat::No) {
+            settings.denoise = !settings.denoise;
+            accumulation.resolve(&mut bitmap, &settings);
+            draw_overlay(&mut bitmap, &camera, &world, &overlay);
+        }
 
-    let spheres = vec![
-        Sphere::new(Vec3::new(0.0, -100.0, 0.0), 100.0, Material::Diffuse { albedo: Vec3::new(0.8, 0.8, 0.0) }),
-        Sphere::new(Vec3::new(-1.0, 0.3, 0.0), 0.3, Material::Metal { albedo: Vec3::new(0.6, 0.6, 0.6), fuzz: 0.4 }),
-        Sphere::new(Vec3::new(0.0, 0.5, 0.0), 0.5, Material::Diffuse { albedo: Vec3::new(0.9, 0.2, 0.2) }),
-        Sphere::new(Vec3::new(1.0, 0.5, 0.0), 0.5, Material::Metal { albedo: Vec3::new(0.4, 0.4, 0.8), fuzz: 0.0 }),
-    ];
-
-    let world = World::new(spheres);
-    let width = bitmap.width();
-    let height = bitmap.height();
-    let camera = Camera::new(
-        Vec3::new(0.0, 2.0, 2.0),
-        Vec3::new(0.0, 0.0, 0.0),
-        Vec3::new(0.0, 1.0, 0.0),
-        60.0,
-        width as f32 / height as f32);
-    let aa_samples = 100;
-
-    bitmap
-        .iter_mut()
-        .par_bridge()
-        .for_each(|(x, y, p)| {
-            let mut random = rand::thread_rng();
-            let mut c = Vec3::zero();
-
-            for _ in 0..aa_samples {
-                let x_scaled = ((x as f32) + random.gen_range(0.0, 1.0)) / (width as f32);
-                let y_scaled = ((y as f32) + random.gen_range(0.0, 1.0)) / (height as f32);
-                c = c + color(&camera.ray(x_scaled, y_scaled), &world, 50);
+        // only the latest update matters when several came in during a pass, a
+        // bad one is skipped and the current scene stays up; the render
+        // settings stay those given at startup
+        if let Some(message) = scene_updates.as_ref().and_then(|updates| updates.try_iter().last()) {
+            match Scene::from_json(&message).and_then(|scene| Ok((scene.camera, scene.world(packets, bvh)?))) {
+                Ok((new_camera_settings, new_world)) => {
+                    for warning in &new_world.warnings {
+                        status!("{}", warning);
+                    }
+                    detail!("{}", new_world.summary());
+                    camera_settings = new_camera_settings;
+                    camera = camera_settings.camera(aspect_ratio);
+                    world = new_world;
+                    accumulation.reset();
+                    reset_stats();
+                    render_start = Instant::now();
+                    budget_spent = false;
+                    status!("Scene updated");
+                }
+                Err(e) => eprintln!("Skipping scene update: {}", e),
             }
+        }
 
-            c = c / aa_samples as f32;
-            c = apply_gamma_2_correction(c);
-            let r = (c.x * std::u8::MAX as f32) as u32;
-            let g = (c.y * std::u8::MAX as f32) as u32;
-            let b = (c.z * std::u8::MAX as f32) as u32;
-            *p = (*p & 0xff000000) | r << 16 | g << 8 | b;
-        });
-}
-
-fn main() -> Result<(), Box<Error>> {
-    let width = 400;
-    let height = 300;
-
-    let mut bitmap = Bitmap::new(width, height);
-    eprintln!("Rendering...");
-    let rendertime = time(|| { render(&mut bitmap) });
-    eprintln!("Render completed ({} ms)", rendertime.as_millis());
+        // movement is scaled by the frame time so holding a key moves smoothly
+        let frame_time = last_frame.elapsed().as_secs_f32();
+        last_frame = Instant::now();
+        let move_speed = 1.5 * frame_time;
+        let turn_speed = 60.0 * frame_time;
+        let axis = |positive: Key, negative: Key| {
+            window.is_key_down(positive) as i32 as f32 - window.is_key_down(negative) as i32 as f32
+        };
+        let forward = axis(Key::W, Key::S);
+        let right = axis(Key::D, Key::A);
+        let yaw = axis(Key::Right, Key::Left);
+        let pitch = axis(Key::Up, Key::Down);
+
+        if forward != 0.0 || right != 0.0 || yaw != 0.0 || pitch != 0.0 {
+            camera_settings.translate(forward * move_speed, right * move_speed);
+            camera_settings.rotate(yaw * turn_speed, pitch * turn_speed);
+            camera = camera_settings.camera(aspect_ratio);
+            accumulation.reset();
+            reset_stats();
+            render_start = Instant::now();
+            budget_spent = false;
+        }
 
-    let mut options = WindowOptions::default();
-    options.scale = Scale::X2;
-    let mut window = Window::new("Raytracer", width, height, options)?;
-    window.update_with_buffer(bitmap.buffer())?;
+        // refine the image by one sample per pixel every frame until done, the
+        // pass runs on its own thread while the window keeps polling so moving
+        // the camera or quitting can cancel a slow one
+        // the first sample is always finished, later passes stop once the time
+        // budget is used up
+        if !paused && !budget_spent && accumulation.samples() < settings.target_samples() {
+            let first = accumulation.samples() == 0;
+            let out_of_time = || budget.is_some_and(|budget| render_start.elapsed() >= budget);
+            let cancel = AtomicBool::new(false);
+            std::thread::scope(|scope| {
+                let pass = scope.spawn(|| render(&mut accumulation, &world, &camera, &settings, 1, &cancel));
+                while !pass.is_finished() {
+                    window.update();
+                    if !window.is_open() || INTERRUPT_KEYS.iter().any(|&key| window.is_key_down(key)) || (!first && out_of_time()) {
+                        cancel.store(true, Ordering::Relaxed);
+                    }
+                    sleep(Duration::from_millis(2));
+                }
+            });
+            accumulation.resolve(&mut bitmap, &settings);
+            draw_overlay(&mut bitmap, &camera, &world, &overlay);
+            budget_spent = out_of_time();
+            if accumulation.samples() == settings.target_samples() || budget_spent {
+                status!("Render completed ({} ms, {:.1} samples per pixel)",
+                          render_start.elapsed().as_millis(), accumulation.average_samples());
+                if stats {
+                    print_stats(render_start.elapsed());
+                }
+            }
+        }
 
-    let event_poll_frequency = 30.0;
-    let millis_per_frame = (1.0 / event_poll_frequency * 1000.0) as i64;
-    let mut event_poll_start = Instant::now();
-    while window.is_open() && !window.is_key_down(Key::Escape) && !window.is_key_down(Key::Q) {
-        window.update_with_buffer(bitmap.buffer())?;
+        let new_title = format!("Raytracer - {}/{} samples{}", accumulation.samples(), settings.target_samples(),
+                                if paused { " (paused)" } else { "" });
+        if new_title != title {
+            window.set_title(&new_title);
+            title = new_title;
+        }
+        show(&mut window, &bitmap)?;
 
         let remaining_ms = millis_per_frame - event_poll_start.elapsed().as_millis() as i64;
         if remaining_ms > 0 {
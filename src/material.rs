@@ -0,0 +1,466 @@
+use std::error::Error;
+use rand::Rng;
+use std::convert::TryFrom;
+use std::sync::Arc;
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+use crate::clamped;
+use crate::geometry::{Vec3, Onb, Ray, Hit};
+
+// pixels are shared between clones so materials stay cheap to copy around
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "String"))]
+pub struct ImageTexture {
+    width: usize,
+    height: usize,
+    pixels: Arc<Vec<Vec3>>,
+}
+
+impl ImageTexture {
+    pub fn load(path: &str) -> Result<ImageTexture, Box<dyn Error>> {
+        let image = image::open(path)?;
+        let (width, height) = (image.width() as usize, image.height() as usize);
+
+        // hdr and exr images are linear already, everything else is decoded with
+        // gamma 2 so it comes out unchanged with the default output gamma
+        let pixels = match image.color() {
+            image::ColorType::Rgb32F | image::ColorType::Rgba32F => image
+                .into_rgb32f()
+                .pixels()
+                .map(|p| Vec3::new(p[0], p[1], p[2]))
+                .collect(),
+            _ => image
+                .into_rgb8()
+                .pixels()
+                .map(|p| {
+                    let c = Vec3::new(p[0] as f32, p[1] as f32, p[2] as f32) / u8::MAX as f32;
+                    c * c
+                })
+                .collect(),
+        };
+
+        Ok(ImageTexture { width, height, pixels: Arc::new(pixels) })
+    }
+
+    // u wraps around so the seam of a sphere is continuous, v is clamped at the poles
+    pub fn nearest(&self, u: f32, v: f32) -> Vec3 {
+        let u = u - u.floor();
+        let v = clamped(v, 0.0, 1.0);
+        let i = ((u * self.width as f32) as usize).min(self.width - 1);
+        let j = (((1.0 - v) * self.height as f32) as usize).min(self.height - 1);
+        self.pixels[j * self.width + i]
+    }
+
+    // blends the four texels around the point by their distance to it, wrapping
+    // and clamping the same way as nearest so the seam and the poles match
+    pub fn value(&self, u: f32, v: f32) -> Vec3 {
+        // texel centers are at half coordinates
+        let x = (u - u.floor()) * self.width as f32 - 0.5;
+        let y = (1.0 - clamped(v, 0.0, 1.0)) * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+
+        let column = |i: f32| (i as isize).rem_euclid(self.width as isize) as usize;
+        let row = |j: f32| (j.max(0.0) as usize).min(self.height - 1);
+        let (i0, i1) = (column(x0), column(x0 + 1.0));
+        let (j0, j1) = (row(y0), row(y0 + 1.0));
+        let texel = |i: usize, j: usize| self.pixels[j * self.width + i];
+
+        let top = texel(i0, j0) * (1.0 - fx) + texel(i1, j0) * fx;
+        let bottom = texel(i0, j1) * (1.0 - fx) + texel(i1, j1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+impl TryFrom<String> for ImageTexture {
+    type Error = String;
+
+    fn try_from(path: String) -> Result<ImageTexture, String> {
+        ImageTexture::load(&path).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+// bilinear is smooth up close, nearest keeps the hard texel edges of pixel art
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum TextureFilter {
+    #[default]
+    Bilinear,
+    Nearest,
+}
+
+// scene files give solid colors as plain arrays, checkers as tables and
+// images as tables with the path, like { image = "earth.jpg" } or
+// { image = "sprite.png", filter = "nearest" }
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(untagged))]
+pub enum Texture {
+    Solid(Vec3),
+    Checker { odd: Vec3, even: Vec3, scale: f32 },
+    Image {
+        image: ImageTexture,
+        #[cfg_attr(feature = "serde", serde(default))]
+        filter: TextureFilter,
+    },
+}
+
+impl Texture {
+    pub fn value(&self, u: f32, v: f32, p: Vec3) -> Vec3 {
+        match self {
+            Texture::Solid(color) => *color,
+            Texture::Image { image, filter: TextureFilter::Bilinear } => image.value(u, v),
+            Texture::Image { image, filter: TextureFilter::Nearest } => image.nearest(u, v),
+            Texture::Checker { odd, even, scale } => {
+                // the product is zero on the axis planes, so surfaces lying exactly
+                // in one of them (like a ground at y = 0) should be moved off it slightly
+                let sines = (scale * p.x).sin() * (scale * p.y).sin() * (scale * p.z).sin();
+                if sines < 0.0 { *odd } else { *even }
+            }
+        }
+    }
+}
+
+pub(crate) struct MaterialScatter {
+    pub(crate) attenuation: Vec3,
+    pub(crate) scattered_ray: Ray
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "lowercase"))]
+pub enum Material {
+    Diffuse { albedo: Texture },
+    Lambertian { albedo: Texture },
+    Metal { albedo: Vec3, fuzz: f32 },
+    // with dispersion red refracts with the index minus it and blue plus it,
+    // absorption is how much of each channel the inside takes away per unit of
+    // distance, which tints thick parts more than thin ones
+    Dielectric {
+        refraction_index: f32,
+        #[cfg_attr(feature = "serde", serde(default))]
+        dispersion: f32,
+        #[cfg_attr(feature = "serde", serde(default = "Vec3::zero"))]
+        absorption: Vec3,
+    },
+    // one sided lights only shine from the front, the outside of spheres and
+    // the side the normal of flat shapes points to
+    Emissive {
+        emit: Vec3,
+        #[cfg_attr(feature = "serde", serde(default = "Material::default_two_sided"))]
+        two_sided: bool,
+    },
+    // scatters the same in all directions, the phase function of fog and smoke
+    Isotropic { albedo: Texture },
+    // a diffuse base with a glossy highlight that is tighter for higher shininess,
+    // diffuse and specular should add up to at most 1 to not gain energy
+    Phong { diffuse: Vec3, specular: Vec3, shininess: f32 },
+    // the metallic/roughness parameters of asset pipelines, metallic picks between
+    // a diffuse and a glossy lobe that is a mirror at zero roughness
+    Pbr { base_color: Vec3, metallic: f32, roughness: f32 },
+    // brushed metal, its highlights stretch along the tangent by roughness_u and
+    // across it by roughness_v, equal ones look like metallic pbr, the tangent is
+    // the surface's own u direction unless a world space one is given
+    AnisotropicMetal {
+        albedo: Vec3,
+        roughness_u: f32,
+        roughness_v: f32,
+        #[cfg_attr(feature = "serde", serde(default))]
+        tangent: Option<Vec3>,
+    },
+    // another material with its shading normals taken from a tangent space
+    // normal map, like { type = "normalmapped", normal_map = "bumps.png", material = { .. } }
+    NormalMapped { normal_map: ImageTexture, material: Box<Material> },
+    // a clear glossy layer over another material, it reflects by the fresnel term
+    // of its index, which is strongest at grazing angles, and lets the rest through
+    Coated {
+        base: Box<Material>,
+        coat_ior: f32,
+        #[cfg_attr(feature = "serde", serde(default))]
+        coat_roughness: f32,
+    },
+}
+
+impl Material {
+    pub(crate) fn scatter(&self, ray: &Ray, hit: &Hit, random: &mut impl Rng) -> Option<MaterialScatter> {
+        // Schlick's approximation of the fresnel reflectance
+        fn schlick(cosine: f32, refraction_index: f32) -> f32 {
+            let r0 = (1.0 - refraction_index) / (1.0 + refraction_index);
+            let r0 = r0 * r0;
+            r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
+        }
+
+        match self {
+            Material::Diffuse { albedo } => {
+                let direction = hit.normal + Vec3::random_in_unit_sphere(random);
+
+                // the random vector can nearly cancel out the normal
+                let direction = if direction.near_zero() { hit.normal } else { direction };
+                Some(MaterialScatter {
+                    attenuation: albedo.value(hit.u, hit.v, hit.position),
+                    scattered_ray: Ray::new(hit.position, direction)
+                })
+            }
+            Material::Lambertian { albedo } => {
+                let direction = Onb::from_w(hit.normal).local(Vec3::random_cosine_direction(random));
+                Some(MaterialScatter {
+                    attenuation: albedo.value(hit.u, hit.v, hit.position),
+                    scattered_ray: Ray::new(hit.position, direction)
+                })
+            }
+            Material::Metal { albedo, fuzz } => {
+                let reflected = ray.direction.unit_vector().reflect(hit.normal);
+                let scattered_ray = Ray::new(hit.position, reflected + clamped(*fuzz, 0.0, 1.0) * Vec3::random_in_unit_sphere(random));
+                if scattered_ray.direction.dot(hit.normal) > 0.0 {
+                    Some(MaterialScatter{
+                        attenuation: *albedo,
+                        scattered_ray
+                    })
+                } else {
+                    None
+                }
+            }
+            Material::Dielectric { refraction_index, dispersion, absorption } => {
+                // dispersive glass follows one randomly picked channel, which is
+                // weighted up by three to make up for the other two, paths that
+                // were narrowed down before keep their channel
+                let (refraction_index, attenuation, channel) = if *dispersion != 0.0 {
+                    let (channel, weight) = match ray.channel {
+                        Some(channel) => (channel, 1.0),
+                        None => (random.gen_range(0, 3), 3.0),
+                    };
+                    let mut attenuation = Vec3::zero();
+                    attenuation.set(channel, weight);
+                    (refraction_index + (channel as f32 - 1.0) * dispersion, attenuation, Some(channel))
+                } else {
+                    (*refraction_index, Vec3::splat(1.0), None)
+                };
+
+                let reflected = ray.direction().reflect(hit.normal);
+                let cosine = clamped(-ray.direction().unit_vector().dot(hit.normal), 0.0, 1.0);
+                let ni_over_nt = if hit.front_face { 1.0 / refraction_index } else { refraction_index };
+
+                let direction = match ray.direction().refract(hit.normal, ni_over_nt) {
+                    Some(refracted) if random.gen::<f32>() >= schlick(cosine, refraction_index) => refracted,
+                    _ => reflected,
+                };
+
+                // a ray hitting the inside has come all the way through the glass
+                // from where it entered, Beer-Lambert dims it over that distance
+                let attenuation = if hit.front_face {
+                    attenuation
+                } else {
+                    attenuation * (-hit.t * ray.direction().length() * *absorption).exp()
+                };
+
+                let mut scattered_ray = Ray::new(hit.position, direction);
+                scattered_ray.channel = channel;
+                Some(MaterialScatter {
+                    attenuation,
+                    scattered_ray
+                })
+            }
+            Material::Emissive { .. } => None,
+            Material::Phong { diffuse, specular, shininess } => {
+                // picks one of the lobes in proportion to how much it reflects, and
+                // divides by that chance to make up for the other one
+                let diffuse_weight = diffuse.max_component();
+                let specular_weight = specular.max_component();
+                let specular_chance = specular_weight / (diffuse_weight + specular_weight).max(1e-6);
+
+                if random.gen::<f32>() >= specular_chance {
+                    let direction = Onb::from_w(hit.normal).local(Vec3::random_cosine_direction(random));
+                    return Some(MaterialScatter {
+                        attenuation: *diffuse / (1.0 - specular_chance),
+                        scattered_ray: Ray::new(hit.position, direction)
+                    });
+                }
+
+                // the lobe is sampled exactly, which together with the normalized
+                // modified phong brdf leaves (n + 2) / (n + 1) times the cosine
+                let reflected = ray.direction().unit_vector().reflect(hit.normal);
+                let direction = Onb::from_w(reflected).local(Vec3::random_phong_direction(*shininess, random));
+                let cosine = direction.dot(hit.normal);
+                if cosine <= 0.0 {
+                    return None;
+                }
+                Some(MaterialScatter {
+                    attenuation: *specular * ((shininess + 2.0) / (shininess + 1.0) * cosine / specular_chance),
+                    scattered_ray: Ray::new(hit.position, direction)
+                })
+            }
+            Material::Pbr { base_color, metallic, roughness } => {
+                // both lobes reflect the base color, so picking one with the chance
+                // of its weight blends them without any extra factor
+                if random.gen::<f32>() >= *metallic {
+                    let direction = Onb::from_w(hit.normal).local(Vec3::random_cosine_direction(random));
+                    return Some(MaterialScatter {
+                        attenuation: *base_color,
+                        scattered_ray: Ray::new(hit.position, direction)
+                    });
+                }
+
+                let roughness = clamped(*roughness, 0.0, 1.0);
+                let microfacet = Onb::from_w(hit.normal).local(Vec3::random_ggx_direction(roughness * roughness, random));
+                let direction = ray.direction().unit_vector().reflect(microfacet);
+                if direction.dot(hit.normal) <= 0.0 {
+                    return None;
+                }
+                Some(MaterialScatter {
+                    attenuation: *base_color,
+                    scattered_ray: Ray::new(hit.position, direction)
+                })
+            }
+            Material::AnisotropicMetal { albedo, roughness_u, roughness_v, tangent } => {
+                let alpha_u = clamped(*roughness_u, 0.0, 1.0).powi(2);
+                let alpha_v = clamped(*roughness_v, 0.0, 1.0).powi(2);
+                let frame = hit.tangent_frame(tangent.unwrap_or(hit.tangent));
+                let microfacet = frame.local(Vec3::random_anisotropic_ggx_direction(alpha_u, alpha_v, random));
+                let direction = ray.direction().unit_vector().reflect(microfacet);
+                if direction.dot(hit.normal) <= 0.0 {
+                    return None;
+                }
+                Some(MaterialScatter {
+                    attenuation: *albedo,
+                    scattered_ray: Ray::new(hit.position, direction)
+                })
+            }
+            Material::Isotropic { albedo } => {
+                let direction = Vec3::random_in_unit_sphere(random).unit_vector();
+                Some(MaterialScatter {
+                    attenuation: albedo.value(hit.u, hit.v, hit.position),
+                    scattered_ray: Ray::new(hit.position, direction)
+                })
+            }
+            // hits are resolved to the material underneath by with_normal_map first
+            Material::NormalMapped { material, .. } => material.scatter(ray, hit, random),
+            Material::Coated { base, coat_ior, coat_roughness } => {
+                // the coat reflects white light, picking it with the chance of its
+                // reflectance leaves the base the rest without any extra factor
+                let cosine = clamped(-ray.direction().unit_vector().dot(hit.normal), 0.0, 1.0);
+                if !hit.front_face || random.gen::<f32>() >= schlick(cosine, *coat_ior) {
+                    return base.scatter(ray, hit, random);
+                }
+
+                let reflected = ray.direction().unit_vector().reflect(hit.normal);
+                let direction = reflected + clamped(*coat_roughness, 0.0, 1.0) * Vec3::random_in_unit_sphere(random);
+                if direction.dot(hit.normal) <= 0.0 {
+                    return None;
+                }
+                Some(MaterialScatter {
+                    attenuation: Vec3::splat(1.0),
+                    scattered_ray: Ray::new(hit.position, direction)
+                })
+            }
+        }
+    }
+
+    pub(crate) fn emitted(&self, hit: &Hit) -> Vec3 {
+        match self {
+            Material::Emissive { emit, two_sided } if *two_sided || hit.front_face => *emit,
+            _ => Vec3::zero(),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    fn default_two_sided() -> bool {
+        true
+    }
+
+    // how much light coming from direction the material sends back along the
+    // ray, cosine included, and how likely scatter is to pick that direction,
+    // for the materials whose bounces can be weighed against light sampling
+    pub(crate) fn evaluate(&self, ray: &Ray, hit: &Hit, direction: Vec3) -> Option<(Vec3, f32)> {
+        use std::f32::consts::PI;
+
+        let direction = direction.unit_vector();
+        let cosine = hit.normal.dot(direction);
+        match self {
+            Material::Lambertian { albedo } => {
+                if cosine <= 0.0 {
+                    return Some((Vec3::zero(), 0.0));
+                }
+                Some((albedo.value(hit.u, hit.v, hit.position) * (cosine / PI), cosine / PI))
+            }
+            Material::Phong { diffuse, specular, shininess } => {
+                if cosine <= 0.0 {
+                    return Some((Vec3::zero(), 0.0));
+                }
+                let specular_chance = specular.max_component() / (diffuse.max_component() + specular.max_component()).max(1e-6);
+                let reflected = ray.direction().unit_vector().reflect(hit.normal);
+                let lobe = reflected.dot(direction).max(0.0).powf(*shininess);
+                let value = *diffuse * (cosine / PI) + *specular * ((shininess + 2.0) / (2.0 * PI) * lobe * cosine);
+                let pdf = (1.0 - specular_chance) * cosine / PI + specular_chance * (shininess + 1.0) / (2.0 * PI) * lobe;
+                Some((value, pdf))
+            }
+            Material::Pbr { base_color, metallic, roughness } => {
+                // nearly smooth metal is a mirror, which light sampling can't hit
+                let alpha = clamped(*roughness, 0.0, 1.0).powi(2);
+                if alpha < 1e-3 {
+                    return None;
+                }
+                if cosine <= 0.0 {
+                    return Some((Vec3::zero(), 0.0));
+                }
+
+                // the ggx normals are picked with D(h) cos(h), which turns into
+                // this density over reflected directions
+                let metallic = clamped(*metallic, 0.0, 1.0);
+                let incoming = ray.direction().unit_vector();
+                let half = (direction - incoming).unit_vector();
+                let cosine_half = half.dot(hit.normal).max(0.0);
+                let d = alpha * alpha / (PI * (cosine_half * cosine_half * (alpha * alpha - 1.0) + 1.0).powi(2));
+                let specular_pdf = d * cosine_half / (4.0 * direction.dot(half).abs().max(1e-6));
+
+                let diffuse_pdf = (1.0 - metallic) * cosine / PI;
+                let pdf = diffuse_pdf + metallic * specular_pdf;
+                Some((*base_color * pdf, pdf))
+            }
+            Material::AnisotropicMetal { albedo, roughness_u, roughness_v, tangent } => {
+                let alpha_u = clamped(*roughness_u, 0.0, 1.0).powi(2);
+                let alpha_v = clamped(*roughness_v, 0.0, 1.0).powi(2);
+                if alpha_u.min(alpha_v) < 1e-3 {
+                    return None;
+                }
+                if cosine <= 0.0 {
+                    return Some((Vec3::zero(), 0.0));
+                }
+
+                // pbr's density with the half vector's slopes measured against
+                // the roughness along and across the tangent
+                let frame = hit.tangent_frame(tangent.unwrap_or(hit.tangent));
+                let incoming = ray.direction().unit_vector();
+                let half = (direction - incoming).unit_vector();
+                let x = half.dot(frame.u) / alpha_u;
+                let y = half.dot(frame.v) / alpha_v;
+                let cosine_half = half.dot(frame.w).max(0.0);
+                let d = 1.0 / (PI * alpha_u * alpha_v * (x * x + y * y + cosine_half * cosine_half).powi(2));
+                let pdf = d * cosine_half / (4.0 * direction.dot(half).abs().max(1e-6));
+                Some((*albedo * pdf, pdf))
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn is_emissive(&self) -> bool {
+        matches!(self, Material::Emissive { .. })
+    }
+
+    // the albedo at a point of materials that are lit directly by lights
+    pub(crate) fn diffuse_albedo(&self, hit: &Hit) -> Option<Vec3> {
+        match self {
+            Material::Diffuse { albedo } | Material::Lambertian { albedo } => Some(albedo.value(hit.u, hit.v, hit.position)),
+            Material::Phong { diffuse, .. } => Some(*diffuse),
+            Material::Pbr { base_color, metallic, .. } => Some(*base_color * (1.0 - clamped(*metallic, 0.0, 1.0))),
+            // dimmed by what the coat reflects head on, it reflects more toward the
+            // edges but the direction the light is seen from isn't known here
+            Material::Coated { base, coat_ior, .. } => {
+                let r0 = ((1.0 - coat_ior) / (1.0 + coat_ior)).powi(2);
+                base.diffuse_albedo(hit).map(|albedo| albedo * (1.0 - r0))
+            }
+            _ => None,
+        }
+    }
+}
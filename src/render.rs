@@ -0,0 +1,2177 @@
+use std::error::Error;
+use std::io::{Read, Write};
+use rand::{Rng, RngCore};
+use std::time::{Instant, Duration};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use rayon::prelude::*;
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+use crate::clamped;
+use crate::geometry::{Vec3, Onb, Ray, HittablePdf, Pdf};
+use crate::bvh::BvhBuild;
+use crate::scene::{World, Projection, Camera, Scene, Environment};
+
+// xoshiro256++ seeded through splitmix64, a generator that is cheap enough to
+// start afresh for every sample, which the chacha of StdRng is not
+struct SampleRng {
+    s: [u64; 4],
+}
+
+impl SampleRng {
+    fn new(mut seed: u64) -> SampleRng {
+        let mut splitmix = || {
+            seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = seed;
+            z = (z ^ z >> 30).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ z >> 27).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ z >> 31
+        };
+        SampleRng { s: [splitmix(), splitmix(), splitmix(), splitmix()] }
+    }
+}
+
+impl RngCore for SampleRng {
+    fn next_u64(&mut self) -> u64 {
+        let s = &mut self.s;
+        let result = s[0].wrapping_add(s[3]).rotate_left(23).wrapping_add(s[0]);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct Bitmap {
+    width: usize,
+    height: usize,
+    buffer: Vec<u32>,
+}
+
+impl Bitmap {
+    pub fn new(width: usize, height: usize) -> Bitmap {
+        Bitmap::try_new(width, height).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new(width: usize, height: usize) -> Result<Bitmap, Box<dyn Error>> {
+        let pixels = width.checked_mul(height).ok_or_else(|| format!("{}x{} is too large", width, height))?;
+        Ok(Bitmap {
+            width,
+            height,
+            buffer: zeroed(0, pixels)?,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    fn get_mut(&mut self, x: usize, y: usize) -> Option<&mut u32> {
+        if x >= self.width || y >= self.height {
+            None
+        } else {
+            Some(&mut self.buffer[(self.height - y - 1) * self.width + (x)])
+        }
+    }
+
+    pub fn buffer(&self) -> &[u32] {
+        &self.buffer
+    }
+
+    // the buffer is stored top row first, which is also the row order of
+    // image files, so only the alpha channel has to be stripped
+    fn rgb_bytes(&self) -> Vec<u8> {
+        self.buffer
+            .iter()
+            .flat_map(|p| [(p >> 16) as u8, (p >> 8) as u8, *p as u8])
+            .collect()
+    }
+
+    pub fn save_png(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        image::save_buffer_with_format(path, &self.rgb_bytes(), self.width as u32, self.height as u32,
+                                       image::ColorType::Rgb8, image::ImageFormat::Png)?;
+        Ok(())
+    }
+
+    // the same with the top byte of every pixel as its alpha
+    pub fn save_png_with_alpha(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let bytes: Vec<u8> = self.buffer
+            .iter()
+            .flat_map(|p| [(p >> 16) as u8, (p >> 8) as u8, *p as u8, (p >> 24) as u8])
+            .collect();
+        image::save_buffer_with_format(path, &bytes, self.width as u32, self.height as u32,
+                                       image::ColorType::Rgba8, image::ImageFormat::Png)?;
+        Ok(())
+    }
+
+    pub fn write_ppm<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        write!(w, "P6\n{} {}\n255\n", self.width, self.height)?;
+        w.write_all(&self.rgb_bytes())
+    }
+
+    // enlarges the bitmap by a whole factor with bilinear filtering between
+    // pixel centers, so it doesn't look blocky
+    pub fn upscaled(&self, factor: usize) -> Bitmap {
+        let mut result = Bitmap::new(self.width * factor, self.height * factor);
+        let source = |x: f32, limit: usize| {
+            let x = clamped(x, 0.0, (limit - 1) as f32);
+            let x0 = x as usize;
+            (x0, (x0 + 1).min(limit - 1), x - x0 as f32)
+        };
+
+        for y in 0..result.height {
+            let (y0, y1, fy) = source((y as f32 + 0.5) / factor as f32 - 0.5, self.height);
+            for x in 0..result.width {
+                let (x0, x1, fx) = source((x as f32 + 0.5) / factor as f32 - 0.5, self.width);
+                let p = |x: usize, y: usize| self.buffer[y * self.width + x];
+                let mut pixel = p(x0, y0) & 0xff000000;
+                for shift in [0, 8, 16] {
+                    let c = |x: usize, y: usize| ((p(x, y) >> shift) & 0xff) as f32;
+                    let top = c(x0, y0) + (c(x1, y0) - c(x0, y0)) * fx;
+                    let bottom = c(x0, y1) + (c(x1, y1) - c(x0, y1)) * fx;
+                    pixel |= ((top + (bottom - top) * fy).round() as u32) << shift;
+                }
+                result.buffer[y * result.width + x] = pixel;
+            }
+        }
+        result
+    }
+}
+
+// guides drawn over the finished image, with --overlay grid,bounds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overlay {
+    // lines a unit apart on the y = 0 plane, the x axis red and the z axis blue
+    Grid,
+    // the bounding boxes of the objects in the scene
+    Bounds,
+}
+
+impl std::str::FromStr for Overlay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Overlay, String> {
+        match s {
+            "grid" => Ok(Overlay::Grid),
+            "bounds" => Ok(Overlay::Bounds),
+            _ => Err(format!("unknown overlay: {}", s)),
+        }
+    }
+}
+
+const GRID_EXTENT: i32 = 10;
+
+// drawn onto the bitmap after it was resolved, so the lines keep their colors
+// whatever the tone map and gamma of the render are
+pub fn draw_overlay(bitmap: &mut Bitmap, camera: &Camera, world: &World, overlays: &[Overlay]) {
+    let mut lines = Vec::new();
+    if overlays.contains(&Overlay::Grid) {
+        let extent = GRID_EXTENT as f32;
+        for i in -GRID_EXTENT..=GRID_EXTENT {
+            let i = i as f32;
+            let (x_color, z_color) = if i == 0.0 {
+                (Vec3::new(0.4, 0.4, 1.0), Vec3::new(1.0, 0.3, 0.3))
+            } else {
+                (Vec3::splat(0.8), Vec3::splat(0.8))
+            };
+            lines.push((Vec3::new(i, 0.0, -extent), Vec3::new(i, 0.0, extent), x_color));
+            lines.push((Vec3::new(-extent, 0.0, i), Vec3::new(extent, 0.0, i), z_color));
+        }
+    }
+    if overlays.contains(&Overlay::Bounds) {
+        let color = Vec3::new(1.0, 0.8, 0.1);
+        for b in &world.bounds {
+            let corner = |i: usize| Vec3::new(
+                if i & 1 == 0 { b.min.x } else { b.max.x },
+                if i & 2 == 0 { b.min.y } else { b.max.y },
+                if i & 4 == 0 { b.min.z } else { b.max.z });
+            // the edges join corners that differ in one coordinate
+            for i in 0..8 {
+                for axis in [1, 2, 4] {
+                    if i & axis == 0 {
+                        lines.push((corner(i), corner(i | axis), color));
+                    }
+                }
+            }
+        }
+    }
+
+    for (a, b, color) in lines {
+        draw_line(bitmap, camera, a, b, color);
+    }
+}
+
+fn draw_line(bitmap: &mut Bitmap, camera: &Camera, mut a: Vec3, mut b: Vec3, color: Vec3) {
+    // cut at a plane just in front of the camera, the part behind has no
+    // sensible projection
+    const NEAR: f32 = 0.01;
+    let (depth_a, depth_b) = (camera.depth(a), camera.depth(b));
+    if depth_a < NEAR && depth_b < NEAR {
+        return;
+    }
+    if depth_a < NEAR {
+        a = a + (b - a) * ((NEAR - depth_a) / (depth_b - depth_a));
+    } else if depth_b < NEAR {
+        b = b + (a - b) * ((NEAR - depth_b) / (depth_a - depth_b));
+    }
+    let (Some(a), Some(b)) = (camera.project(a), camera.project(b)) else {
+        return;
+    };
+
+    let (width, height) = (bitmap.width() as f32, bitmap.height() as f32);
+    let (ax, ay, bx, by) = (a.0 * width, a.1 * height, b.0 * width, b.1 * height);
+    let (dx, dy) = (bx - ax, by - ay);
+    let length_squared = (dx * dx + dy * dy).max(1e-12);
+
+    // steps along the longer axis and looks at the few pixels across the line
+    // there, each covered by how close its center is to the line
+    let steep = dy.abs() > dx.abs();
+    let (from, to) = if steep { (ay.min(by), ay.max(by)) } else { (ax.min(bx), ax.max(bx)) };
+    let limit = if steep { height } else { width };
+    let (from, to) = (from.floor().max(0.0) as i64, to.ceil().min(limit) as i64);
+    for major in from..to {
+        let center = major as f32 + 0.5;
+        let t = clamped((center - if steep { ay } else { ax }) / if steep { dy } else { dx }, 0.0, 1.0);
+        let minor_center = if steep { ax + t * dx } else { ay + t * dy };
+        for minor in (minor_center - 1.5).floor() as i64..=(minor_center + 1.5).ceil() as i64 {
+            let (x, y) = if steep { (minor, major) } else { (major, minor) };
+            if x < 0 || y < 0 {
+                continue;
+            }
+
+            // distance from the pixel center to the closest point of the segment
+            let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+            let s = clamped(((px - ax) * dx + (py - ay) * dy) / length_squared, 0.0, 1.0);
+            let (ex, ey) = (px - (ax + s * dx), py - (ay + s * dy));
+            let coverage = clamped(1.0 - (ex * ex + ey * ey).sqrt(), 0.0, 1.0);
+            if coverage <= 0.0 {
+                continue;
+            }
+            if let Some(p) = bitmap.get_mut(x as usize, y as usize) {
+                let blend = |shift: u32, value: f32| {
+                    let old = ((*p >> shift) & 0xff) as f32;
+                    ((old + (value * u8::MAX as f32 - old) * coverage) as u32) << shift
+                };
+                *p = blend(24, 1.0) | blend(16, color.x) | blend(8, color.y) | blend(0, color.z);
+            }
+        }
+    }
+}
+
+// paths shorter than this are never cut short
+const ROULETTE_DEPTH: usize = 3;
+
+// counters for --stats, they are only updated when enabled so they cost next
+// to nothing otherwise
+pub(crate) struct Stats {
+    enabled: AtomicBool,
+    primary_rays: AtomicU64,
+    scattered_rays: AtomicU64,
+    sphere_tests: AtomicU64,
+}
+
+pub(crate) static STATS: Stats = Stats {
+    enabled: AtomicBool::new(false),
+    primary_rays: AtomicU64::new(0),
+    scattered_rays: AtomicU64::new(0),
+    sphere_tests: AtomicU64::new(0),
+};
+
+impl Stats {
+    fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    fn record_paths(&self, paths: usize, bounces: usize) {
+        if self.enabled() {
+            self.primary_rays.fetch_add(paths as u64, Ordering::Relaxed);
+            self.scattered_rays.fetch_add(bounces as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_sphere_test(&self) {
+        self.record_sphere_tests(1);
+    }
+
+    pub(crate) fn record_sphere_tests(&self, count: usize) {
+        if self.enabled() {
+            self.sphere_tests.fetch_add(count as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn reset(&self) {
+        self.primary_rays.store(0, Ordering::Relaxed);
+        self.scattered_rays.store(0, Ordering::Relaxed);
+        self.sphere_tests.store(0, Ordering::Relaxed);
+    }
+
+    fn print(&self, elapsed: Duration) {
+        let primary = self.primary_rays.load(Ordering::Relaxed);
+        let scattered = self.scattered_rays.load(Ordering::Relaxed);
+        let rays = primary + scattered;
+        eprintln!("{} primary rays, {} scattered rays, {:.2} bounces per path on average",
+                  primary, scattered, scattered as f64 / primary.max(1) as f64);
+        eprintln!("{} ray-sphere tests, {:.2} million rays per second",
+                  self.sphere_tests.load(Ordering::Relaxed), rays as f64 / elapsed.as_secs_f64().max(1e-9) / 1e6);
+    }
+}
+
+// --stats, the counters add up over renders until they are reset
+pub fn set_stats_enabled(enabled: bool) {
+    STATS.set_enabled(enabled);
+}
+
+pub fn reset_stats() {
+    STATS.reset();
+}
+
+pub fn print_stats(elapsed: Duration) {
+    STATS.print(elapsed);
+}
+
+// what a path sees, along with the albedo and normal where it first hits
+// something, which guide the denoiser
+pub struct Sample {
+    pub color: Vec3,
+    pub albedo: Vec3,
+    pub normal: Vec3,
+    // how many times the path scattered
+    pub bounces: usize,
+    // 1 when the camera ray hit something and 0 when it saw the background,
+    // summed into the alpha of the pixel
+    pub coverage: f32,
+    // the part of color that reached the camera straight from a light or the
+    // background or after a single bounce, the rest is indirect
+    pub direct: Vec3,
+    // how far away the first hit is, 0 for the background
+    pub distance: f32,
+}
+
+impl Sample {
+    fn zero() -> Sample {
+        Sample {
+            color: Vec3::zero(),
+            albedo: Vec3::zero(),
+            normal: Vec3::zero(),
+            bounces: 0,
+            coverage: 0.0,
+            direct: Vec3::zero(),
+            distance: 0.0,
+        }
+    }
+}
+
+// follows a path until it leaves the scene, stops scattering or runs out of
+// bounces, throughput is the product of the attenuations along it so far
+pub fn color(mut ray: Ray, world: &World, settings: &RenderSettings, random: &mut impl Rng) -> Sample {
+    if settings.mode != RenderMode::Path {
+        return debug_color(ray, world, settings, random);
+    }
+
+    let mut throughput = Vec3::splat(1.0);
+    let mut result = Sample::zero();
+
+    // what the camera sees and the light arriving straight at the first hit are
+    // left alone, only the light found after that is clamped, or dropped when it
+    // isn't finite as scaling down an infinity gives NaN
+    let clamp = |contribution: Vec3, depth: usize| match settings.firefly_clamp {
+        Some(_) if depth >= 2 && !contribution.is_finite() => Vec3::zero(),
+        Some(limit) if depth >= 2 && contribution.max_component() > limit => contribution * (limit / contribution.max_component()),
+        _ => contribution,
+    };
+
+    // the power heuristic, how much of a sample to keep when the other strategy
+    // could have found the same light
+    let power = |pdf: f32, other: f32| pdf * pdf / (pdf * pdf + other * other);
+
+    // where the last bounce left from and how likely the material was to pick
+    // its direction, when that bounce also sampled the lights
+    let mut last_bounce: Option<(Vec3, f32)> = None;
+
+    for depth in 0..=settings.max_bounces {
+        let hit = match world.hit_test(&ray, settings.ray_epsilon, f32::MAX, random) {
+            Some(hit) => hit.with_normal_map(),
+            None => {
+                let background = settings.background(ray.direction);
+                if depth == 0 {
+                    result.albedo = background;
+                    // the background is left out of transparent images
+                    if settings.alpha {
+                        return result;
+                    }
+                }
+                let contribution = clamp(throughput * background, depth);
+                result.color += contribution;
+                if depth <= 1 {
+                    result.direct += contribution;
+                }
+                return result;
+            }
+        };
+        if depth == 0 {
+            result.normal = hit.normal;
+            result.albedo = hit.material.emitted(&hit);
+            result.coverage = 1.0;
+            result.distance = hit.t * ray.direction().length();
+        }
+
+        // lights found by a bounce that also sampled them only count for the
+        // share the heuristic leaves to the material
+        let mut emitted = hit.material.emitted(&hit);
+        if let Some((origin, material_pdf)) = last_bounce {
+            if !emitted.near_zero() {
+                let light_pdf = HittablePdf::new(origin, settings.ray_epsilon, &world.lights).value(ray.direction, random);
+                emitted *= power(material_pdf, light_pdf);
+            }
+        }
+        // a light seen after one bounce is direct, the point lights lighting
+        // where that bounce went are not
+        if depth == 1 {
+            result.direct += throughput * emitted;
+        }
+        if let Some(albedo) = hit.material.diffuse_albedo(&hit) {
+            emitted += albedo * world.direct_lighting(hit.position, hit.normal, settings.ray_epsilon, random);
+        }
+        let contribution = clamp(throughput * emitted, depth);
+        result.color += contribution;
+        if depth == 0 {
+            result.direct += contribution;
+        }
+        if depth == settings.max_bounces {
+            break;
+        }
+
+        // one direction toward a light as well, weighted against the chance of
+        // the material bouncing the same way
+        let lights = HittablePdf::new(hit.position, settings.ray_epsilon, &world.lights);
+        let sample_lights = !world.lights.is_empty() && hit.material.evaluate(&ray, &hit, hit.normal).is_some();
+        if sample_lights {
+            let direction = lights.generate(random);
+            let light_pdf = lights.value(direction, random);
+            if let Some((value, material_pdf)) = hit.material.evaluate(&ray, &hit, direction) {
+                if light_pdf > 0.0 && material_pdf > 0.0 {
+                    let light = world
+                        .hit_test(&Ray::new(hit.position, direction), settings.ray_epsilon, f32::MAX, random)
+                        .map_or(Vec3::zero(), |light| {
+                            let light = light.with_normal_map();
+                            light.material.emitted(&light)
+                        });
+                    let weight = power(light_pdf, material_pdf) / light_pdf;
+                    let contribution = clamp(throughput * value * light * weight, depth + 1);
+                    result.color += contribution;
+                    if depth == 0 {
+                        result.direct += contribution;
+                    }
+                }
+            }
+        }
+
+        let scatter = match hit.material.scatter(&ray, &hit, random) {
+            Some(scatter) => scatter,
+            None => break,
+        };
+        let mut attenuation = scatter.attenuation;
+        let scattered_ray = scatter.scattered_ray;
+        if depth == 0 {
+            result.albedo = attenuation;
+        }
+        last_bounce = if sample_lights {
+            hit.material
+                .evaluate(&ray, &hit, scattered_ray.direction)
+                .map(|(_, material_pdf)| (hit.position, material_pdf))
+        } else {
+            None
+        };
+
+        // russian roulette, dim paths are likely to stop here and the ones that
+        // survive are boosted by the same amount so the average stays the same
+        if settings.russian_roulette && depth >= ROULETTE_DEPTH {
+            let survival = (throughput * attenuation).max_component().min(1.0);
+            if random.gen::<f32>() >= survival {
+                break;
+            }
+            attenuation = attenuation / survival;
+        }
+
+        throughput *= attenuation;
+        let channel = ray.channel;
+        ray = scattered_ray;
+        ray.channel = ray.channel.or(channel);
+        result.bounces += 1;
+    }
+
+    result
+}
+
+// where the ray passes closer to the outline of a sphere than half a pixel,
+// its color is blended from a ray just inside the outline and one just outside
+// by the share of the pixel the sphere covers; the closest such sphere counts
+fn fast_aa_color(ray: Ray, world: &World, camera: &Camera, settings: &RenderSettings, width: usize, random: &mut impl Rng) -> Sample {
+    let direction = ray.direction().unit_vector();
+    let mut edge: Option<(f32, Vec3, Vec3, Vec3, f32)> = None;
+    for &(center, radius) in &world.silhouettes {
+        let along = (center - ray.origin()).dot(direction);
+        if along <= radius || edge.is_some_and(|(closest, ..)| along >= closest) {
+            continue;
+        }
+
+        // a straight edge at this distance from the middle of a pixel covers it
+        // by this much, the footprint is the pixel's width at the sphere
+        let closest = ray.origin() + direction * along;
+        let offset = closest - center;
+        let distance = offset.length();
+        let footprint = camera.footprint(along, width);
+        let coverage = clamped(0.5 - (distance - radius) / footprint, 0.0, 1.0);
+        if coverage > 0.0 && coverage < 1.0 && distance > 0.0 {
+            let outward = offset / distance;
+            let inside = center + outward * (radius - 0.25 * footprint);
+            let outside = center + outward * (radius + 0.25 * footprint);
+            edge = Some((along, closest, inside, outside, coverage));
+        }
+    }
+    let Some((_, closest, inside, outside, coverage)) = edge else {
+        return color(ray, world, settings, random);
+    };
+
+    // parallel rays move sideways to pass through the point, the others turn
+    let through = |point: Vec3| match camera.projection {
+        Projection::Orthographic { .. } => Ray::new(ray.origin() + (point - closest), ray.direction()),
+        _ => Ray::new(ray.origin(), point - ray.origin()),
+    };
+    let a = color(through(inside), world, settings, random);
+    let b = color(through(outside), world, settings, random);
+    let mix_vec3 = |a: Vec3, b: Vec3| coverage * a + (1.0 - coverage) * b;
+    let mix = |a: f32, b: f32| coverage * a + (1.0 - coverage) * b;
+    Sample {
+        color: mix_vec3(a.color, b.color),
+        albedo: mix_vec3(a.albedo, b.albedo),
+        normal: mix_vec3(a.normal, b.normal),
+        bounces: a.bounces + b.bounces,
+        coverage: mix(a.coverage, b.coverage),
+        direct: mix_vec3(a.direct, b.direct),
+        distance: mix(a.distance, b.distance),
+    }
+}
+
+// the debug modes show a property of the first hit instead of lighting
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum RenderMode {
+    #[default]
+    Path,
+    Normals,
+    Depth,
+    Albedo,
+    // how many bvh nodes the camera rays visit, from blue for none to red for
+    // BVH_HEAT_MAX or more
+    #[cfg_attr(feature = "serde", serde(rename = "bvh-heat"))]
+    BvhHeat,
+    // ambient occlusion, how much of the hemisphere over the first hit is open
+    // out to ao_radius, from black for none to white
+    Ao,
+}
+
+const BVH_HEAT_MAX: usize = 64;
+
+// ambient occlusion casts a ray in every cell of this by this grid over the hemisphere
+const AO_GRID: usize = 4;
+
+impl std::str::FromStr for RenderMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RenderMode, String> {
+        match s {
+            "path" => Ok(RenderMode::Path),
+            "normals" => Ok(RenderMode::Normals),
+            "depth" => Ok(RenderMode::Depth),
+            "albedo" => Ok(RenderMode::Albedo),
+            "bvh-heat" => Ok(RenderMode::BvhHeat),
+            "ao" => Ok(RenderMode::Ao),
+            _ => Err(format!("unknown mode: {}", s)),
+        }
+    }
+}
+
+fn debug_color(ray: Ray, world: &World, settings: &RenderSettings, random: &mut impl Rng) -> Sample {
+    let mut result = Sample::zero();
+    if settings.mode == RenderMode::BvhHeat {
+        // blue through green to red
+        let heat = clamped(world.nodes_visited(&ray, settings.ray_epsilon, f32::MAX, random) as f32 / BVH_HEAT_MAX as f32, 0.0, 1.0);
+        let red = clamped(2.0 * heat - 1.0, 0.0, 1.0);
+        let blue = clamped(1.0 - 2.0 * heat, 0.0, 1.0);
+        result.color = Vec3::new(red, 1.0 - red - blue, blue);
+        result.albedo = result.color;
+        result.coverage = 1.0;
+        return result;
+    }
+
+    let hit = match world.hit_test(&ray, settings.ray_epsilon, f32::MAX, random) {
+        Some(hit) => hit.with_normal_map(),
+        None => {
+            if !settings.alpha {
+                result.color = match settings.mode {
+                    RenderMode::Ao => Vec3::splat(1.0),
+                    _ => settings.background(ray.direction),
+                };
+            }
+            return result;
+        }
+    };
+    result.coverage = 1.0;
+
+    // normals point outwards here even when the ray hit the back, so flipped
+    // geometry shows up
+    let outward_normal = if hit.front_face { hit.normal } else { -hit.normal };
+    result.normal = hit.normal;
+    result.color = match settings.mode {
+        RenderMode::Normals => 0.5 * (outward_normal + Vec3::splat(1.0)),
+        RenderMode::Depth => Vec3::splat(1.0 / (1.0 + hit.t * ray.direction().length())),
+        RenderMode::Ao => {
+            // cosine weighted like diffuse light comes in, one direction per cell
+            let frame = Onb::from_w(hit.normal);
+            let mut open = 0;
+            for cell in 0..AO_GRID * AO_GRID {
+                let r1 = ((cell % AO_GRID) as f32 + random.gen::<f32>()) / AO_GRID as f32;
+                let r2 = ((cell / AO_GRID) as f32 + random.gen::<f32>()) / AO_GRID as f32;
+                let occluder = Ray::new(hit.position, frame.local(Vec3::cosine_direction(r1, r2)));
+                if world.hit_test(&occluder, settings.ray_epsilon, settings.ao_radius, random).is_none() {
+                    open += 1;
+                }
+            }
+            Vec3::splat(open as f32 / (AO_GRID * AO_GRID) as f32)
+        }
+        _ => match hit.material.scatter(&ray, &hit, random) {
+            Some(scatter) => scatter.attenuation,
+            None => hit.material.emitted(&hit),
+        },
+    };
+    result.albedo = result.color;
+    result
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct RenderSettings {
+    pub samples_per_pixel: usize,
+    pub max_bounces: usize,
+    // a random seed is picked for every render when not set
+    pub seed: Option<u64>,
+    pub tile_size: usize,
+    pub gamma: f32,
+    // turns the environment around the y axis, in degrees counterclockwise seen
+    // from above, to light the scene from a different side
+    pub env_rotation: f32,
+    // in stops, every one doubles the brightness of the image before it is tone
+    // mapped, hdr files keep the radiance as rendered
+    pub exposure: f32,
+    pub environment: Environment,
+    // when set, pixels stop taking samples once they have converged
+    pub adaptive: Option<AdaptiveSampling>,
+    pub denoise: bool,
+    pub tone_map: ToneMap,
+    // gamma only applies to the gamma transfer
+    pub transfer: Transfer,
+    pub stratified: bool,
+    pub mode: RenderMode,
+    // how far away geometry still occludes in ao mode
+    pub ao_radius: f32,
+    pub filter: Filter,
+    // caps what each bounce after the first adds to a path, which removes most
+    // fireflies but also some energy, so the image gets darker than it should
+    pub firefly_clamp: Option<f32>,
+    // the image is rendered this many times wider and taller and averaged down,
+    // the accumulation is that size while the output keeps its own
+    pub ssaa: usize,
+    // rendering stops after this long even if not all samples were taken, the
+    // first sample of every pixel is always finished
+    pub time_budget_ms: Option<u64>,
+    // the background is transparent instead of showing the environment, and
+    // the output gets an alpha channel from how much of each pixel is covered
+    pub alpha: bool,
+    // how far along a ray hits have to be, so one leaving a surface doesn't hit
+    // that surface again, which shows as shadow acne. 0.001 suits scenes around
+    // a few units across, scenes in the thousands need about 0.1 and ones a
+    // centimeter across 1e-6 or less, where too large a value lets light leak
+    // through corners and contacts
+    pub ray_epsilon: f32,
+    // one ray through the middle of every pixel, with the outlines of spheres
+    // smoothed by how much of the pixel they cover instead of by more samples,
+    // a quick preview that leaves the shading noisy
+    pub fast_aa: bool,
+    // only the pixels inside are sampled, the others stay as they were, which
+    // is black in a new render
+    pub region: Option<Region>,
+    // paths are cut short at random once they get dim, which keeps the average
+    // but makes it noisier; off it is only good for checking that
+    pub russian_roulette: bool,
+    // how much rendering prints to stderr, 0 nothing, 1 the progress and 2 the
+    // time every tile took, set by the caller rather than by scene files
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub verbosity: usize,
+}
+
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct AdaptiveSampling {
+    pub min_samples: usize,
+    pub max_samples: usize,
+    // the standard error of a pixel relative to its brightness it settles for
+    pub tolerance: f32,
+}
+
+impl RenderSettings {
+    // what a ray sees that misses everything, the direction is turned back by
+    // the rotation as that turns the environment instead
+    fn background(&self, direction: Vec3) -> Vec3 {
+        let (sin, cos) = (-self.env_rotation).to_radians().sin_cos();
+        self.environment.value(Vec3::new(cos * direction.x + sin * direction.z, direction.y, -sin * direction.x + cos * direction.z))
+    }
+
+    pub fn target_samples(&self) -> usize {
+        match &self.adaptive {
+            _ if self.fast_aa => 1,
+            Some(adaptive) => adaptive.max_samples,
+            None => self.samples_per_pixel,
+        }
+    }
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            samples_per_pixel: 100,
+            max_bounces: 50,
+            seed: None,
+            tile_size: 32,
+            gamma: 2.0,
+            env_rotation: 0.0,
+            exposure: 0.0,
+            environment: Environment::default(),
+            adaptive: None,
+            denoise: false,
+            tone_map: ToneMap::Clamp,
+            transfer: Transfer::Gamma,
+            stratified: false,
+            mode: RenderMode::Path,
+            ao_radius: 1.0,
+            filter: Filter::Box,
+            firefly_clamp: None,
+            ssaa: 1,
+            time_budget_ms: None,
+            ray_epsilon: 0.001,
+            alpha: false,
+            fast_aa: false,
+            region: None,
+            russian_roulette: true,
+            verbosity: 1,
+        }
+    }
+}
+
+// a rectangle of the output from x0, y0 up to but not including x1, y1, counted
+// from the top left like the saved images, region = [x0, y0, x1, y1] in scenes
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(from = "[usize; 4]"))]
+pub struct Region {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl From<[usize; 4]> for Region {
+    fn from(r: [usize; 4]) -> Region {
+        Region { x0: r[0], y0: r[1], x1: r[2], y1: r[3] }
+    }
+}
+
+impl std::str::FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Region, String> {
+        let corners: Vec<usize> = s.split(',').map(|c| c.trim().parse()).collect::<Result<_, _>>()
+            .map_err(|_| format!("invalid region: {}", s))?;
+        match corners[..] {
+            [x0, y0, x1, y1] => Ok(Region { x0, y0, x1, y1 }),
+            _ => Err(format!("a region is x0,y0,x1,y1, not {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Region {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{},{},{},{}", self.x0, self.y0, self.x1, self.y1)
+    }
+}
+
+impl Region {
+    // whether it covers at least a pixel and lies inside an image of the size
+    pub fn fits(&self, width: usize, height: usize) -> bool {
+        self.x0 < self.x1 && self.y0 < self.y1 && self.x1 <= width && self.y1 <= height
+    }
+
+    // the pixels it covers in an accumulation of the size, which is supersampled
+    // by ssaa and has its bottom row first
+    fn tile(&self, width: usize, height: usize, ssaa: usize) -> Tile {
+        let x0 = (self.x0 * ssaa).min(width);
+        let x1 = (self.x1 * ssaa).clamp(x0, width);
+        let y0 = height.saturating_sub(self.y1 * ssaa);
+        let y1 = height.saturating_sub(self.y0 * ssaa).max(y0);
+        Tile { x: x0, y: y0, width: x1 - x0, height: y1 - y0 }
+    }
+}
+
+// how samples are weighted by where in the pixel they are, the wider filters
+// take their samples from around the pixel and count the ones nearer its
+// center more, which blurs edges a little less than averaging the pixel
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Filter {
+    #[default]
+    Box,
+    Tent,
+    Gaussian,
+}
+
+impl std::str::FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Filter, String> {
+        match s {
+            "box" => Ok(Filter::Box),
+            "tent" => Ok(Filter::Tent),
+            "gaussian" => Ok(Filter::Gaussian),
+            _ => Err(format!("unknown filter: {}", s)),
+        }
+    }
+}
+
+impl Filter {
+    // turns a jitter in [0, 1) into an offset from the pixel's corner and the
+    // weight of a sample there
+    fn sample(self, jitter_x: f32, jitter_y: f32) -> (f32, f32, f32) {
+        let radius = match self {
+            // kept apart so the plain average stays exactly what it was
+            Filter::Box => return (jitter_x, jitter_y, 1.0),
+            Filter::Tent => 1.0,
+            Filter::Gaussian => 1.5,
+        };
+        let dx = (jitter_x - 0.5) * 2.0 * radius;
+        let dy = (jitter_y - 0.5) * 2.0 * radius;
+        let weight = match self {
+            Filter::Tent => (1.0 - dx.abs()) * (1.0 - dy.abs()),
+            _ => (-(dx * dx + dy * dy) / (2.0 * 0.5 * 0.5)).exp(),
+        };
+        (0.5 + dx, 0.5 + dy, weight)
+    }
+}
+
+struct Tile {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Tile {
+    // the part of the tile inside another one, if any
+    fn intersection(&self, other: &Tile) -> Option<Tile> {
+        let x0 = self.x.max(other.x);
+        let y0 = self.y.max(other.y);
+        let x1 = (self.x + self.width).min(other.x + other.width);
+        let y1 = (self.y + self.height).min(other.y + other.height);
+        if x0 >= x1 || y0 >= y1 {
+            return None;
+        }
+        Some(Tile { x: x0, y: y0, width: x1 - x0, height: y1 - y0 })
+    }
+}
+
+// the position along a z-order curve, the bits of x and y interleaved, which
+// keeps points that are close together close in the order too
+fn morton(x: usize, y: usize) -> u64 {
+    let spread = |v: usize| {
+        let mut v = v as u64 & 0xffff_ffff;
+        v = (v | v << 16) & 0x0000_ffff_0000_ffff;
+        v = (v | v << 8) & 0x00ff_00ff_00ff_00ff;
+        v = (v | v << 4) & 0x0f0f_0f0f_0f0f_0f0f;
+        v = (v | v << 2) & 0x3333_3333_3333_3333;
+        (v | v << 1) & 0x5555_5555_5555_5555
+    };
+    spread(x) | spread(y) << 1
+}
+
+// a shuffle of 0..n picked by the seed, from Kensler's correlated multi-jittered
+// sampling, so the first few cells of a grid are spread over all of it
+fn permute(mut i: u32, n: u32, seed: u32) -> u32 {
+    let mut w = n - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < n {
+            return i.wrapping_add(seed) % n;
+        }
+    }
+}
+
+// tiles covering the image in z-order, so neighbouring tiles are rendered
+// around the same time and a cut short pass fills in blocks, the ones at the
+// right and top edges are cut to fit
+fn tiles(width: usize, height: usize, tile_size: usize) -> Vec<Tile> {
+    let mut tiles = Vec::new();
+    for y in (0..height).step_by(tile_size) {
+        for x in (0..width).step_by(tile_size) {
+            tiles.push(Tile {
+                x,
+                y,
+                width: tile_size.min(width - x),
+                height: tile_size.min(height - y),
+            });
+        }
+    }
+    tiles.sort_by_key(|tile| morton(tile.x / tile_size, tile.y / tile_size));
+    tiles
+}
+
+// how linear colors above 1 are brought into the displayable range
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum ToneMap {
+    #[default]
+    Clamp,
+    Reinhard,
+    Aces,
+}
+
+impl std::str::FromStr for ToneMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ToneMap, String> {
+        match s {
+            "clamp" => Ok(ToneMap::Clamp),
+            "reinhard" => Ok(ToneMap::Reinhard),
+            "aces" => Ok(ToneMap::Aces),
+            _ => Err(format!("unknown tone map: {}", s)),
+        }
+    }
+}
+
+fn tone_map(c: Vec3, op: ToneMap) -> Vec3 {
+    let map = |x: f32| match op {
+        ToneMap::Clamp => x,
+        ToneMap::Reinhard => x / (1.0 + x),
+        // Narkowicz's fit of the ACES filmic curve
+        ToneMap::Aces => (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),
+    };
+    Vec3::new(map(c.x), map(c.y), map(c.z)).clamp01()
+}
+
+fn gamma_correct(c: Vec3, gamma: f32) -> Vec3 {
+    let exponent = 1.0 / gamma;
+    Vec3::new(c.x.powf(exponent), c.y.powf(exponent), c.z.powf(exponent))
+}
+
+// how linear colors are encoded for display, a plain gamma curve or the
+// piecewise one of the srgb standard that other renderers' images use
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Transfer {
+    #[default]
+    Gamma,
+    Srgb,
+}
+
+impl std::str::FromStr for Transfer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Transfer, String> {
+        match s {
+            "gamma" => Ok(Transfer::Gamma),
+            "srgb" => Ok(Transfer::Srgb),
+            _ => Err(format!("unknown transfer function: {}", s)),
+        }
+    }
+}
+
+fn srgb_encode(c: Vec3) -> Vec3 {
+    let encode = |x: f32| if x <= 0.0031308 { 12.92 * x } else { 1.055 * x.powf(1.0 / 2.4) - 0.055 };
+    Vec3::new(encode(c.x), encode(c.y), encode(c.z))
+}
+
+// the parts of the image that can be saved on their own next to the combined
+// one, the direct and indirect passes add up to it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aov {
+    Direct,
+    Indirect,
+    Albedo,
+    Normals,
+    Depth,
+}
+
+impl Aov {
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+            Aov::Albedo => "albedo",
+            Aov::Normals => "normals",
+            Aov::Depth => "depth",
+        }
+    }
+}
+
+impl std::str::FromStr for Aov {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Aov, String> {
+        match s {
+            "direct" => Ok(Aov::Direct),
+            "indirect" => Ok(Aov::Indirect),
+            "albedo" => Ok(Aov::Albedo),
+            "normals" => Ok(Aov::Normals),
+            "depth" => Ok(Aov::Depth),
+            _ => Err(format!("unknown pass: {}", s)),
+        }
+    }
+}
+
+// every buffer is its own allocation, reserved up front so running out of
+// memory is an error rather than an abort
+fn zeroed<T: Clone>(value: T, len: usize) -> Result<Vec<T>, Box<dyn Error>> {
+    let mut values = Vec::new();
+    values.try_reserve_exact(len).map_err(|_| format!("not enough memory for {} pixels", len))?;
+    values.resize(len, value);
+    Ok(values)
+}
+
+// the running sum of all samples taken so far, laid out like the bitmap buffer
+const CHECKPOINT_MAGIC: &[u8; 8] = b"kekacc6\n";
+
+pub struct Accumulation {
+    width: usize,
+    height: usize,
+    samples: usize,
+    buffer: Vec<Vec3>,
+    // the sums of the filter weights the colors in buffer were added with
+    weights: Vec<f32>,
+    // per pixel sample counts and sums of squared samples for adaptive sampling,
+    // the squares weighted like the colors so they estimate the same mean
+    counts: Vec<usize>,
+    squares: Vec<Vec3>,
+    // sums of the first hit albedos and normals for the denoiser
+    albedo: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    // the sums of the sample coverages, weighted like the colors
+    coverage: Vec<f32>,
+    // the direct part of the colors, weighted the same, and the distances to
+    // the first hits weighted by the coverage too so the background is left out
+    direct: Vec<Vec3>,
+    distance: Vec<f32>,
+}
+
+// one row of the buffers of an accumulation, y counts up from the bottom
+struct Row<'a> {
+    y: usize,
+    buffer: &'a mut [Vec3],
+    weights: &'a mut [f32],
+    counts: &'a mut [usize],
+    squares: &'a mut [Vec3],
+    albedo: &'a mut [Vec3],
+    normals: &'a mut [Vec3],
+    coverage: &'a mut [f32],
+    direct: &'a mut [Vec3],
+    distance: &'a mut [f32],
+}
+
+impl Row<'_> {
+    fn set(&mut self, x: usize, (sum, weights, squares, samples): &(Sample, f32, Vec3, usize)) {
+        self.buffer[x] = sum.color;
+        self.weights[x] = *weights;
+        self.albedo[x] = sum.albedo;
+        self.normals[x] = sum.normal;
+        self.coverage[x] = sum.coverage;
+        self.direct[x] = sum.direct;
+        self.distance[x] = sum.distance;
+        self.squares[x] = *squares;
+        self.counts[x] = *samples;
+    }
+}
+
+impl Accumulation {
+    pub fn new(width: usize, height: usize) -> Accumulation {
+        Accumulation::try_new(width, height).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    // an error instead of aborting when there isn't enough memory
+    pub fn try_new(width: usize, height: usize) -> Result<Accumulation, Box<dyn Error>> {
+        let pixels = width.checked_mul(height).ok_or_else(|| format!("{}x{} is too large", width, height))?;
+        Ok(Accumulation {
+            width,
+            height,
+            samples: 0,
+            buffer: zeroed(Vec3::zero(), pixels)?,
+            weights: zeroed(0.0, pixels)?,
+            counts: zeroed(0, pixels)?,
+            squares: zeroed(Vec3::zero(), pixels)?,
+            albedo: zeroed(Vec3::zero(), pixels)?,
+            normals: zeroed(Vec3::zero(), pixels)?,
+            coverage: zeroed(0.0, pixels)?,
+            direct: zeroed(Vec3::zero(), pixels)?,
+            distance: zeroed(0.0, pixels)?,
+        })
+    }
+
+    // roughly what try_new allocates, to check against the memory there is
+    pub fn memory_needed(width: usize, height: usize) -> Option<usize> {
+        let per_pixel = 5 * std::mem::size_of::<Vec3>() + 3 * std::mem::size_of::<f32>() + std::mem::size_of::<usize>();
+        width.checked_mul(height)?.checked_mul(per_pixel)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    pub fn reset(&mut self) {
+        self.samples = 0;
+        self.buffer.iter_mut().for_each(|c| *c = Vec3::zero());
+        self.weights.iter_mut().for_each(|w| *w = 0.0);
+        self.counts.iter_mut().for_each(|n| *n = 0);
+        self.squares.iter_mut().for_each(|c| *c = Vec3::zero());
+        self.albedo.iter_mut().for_each(|c| *c = Vec3::zero());
+        self.normals.iter_mut().for_each(|c| *c = Vec3::zero());
+        self.coverage.iter_mut().for_each(|a| *a = 0.0);
+        self.direct.iter_mut().for_each(|c| *c = Vec3::zero());
+        self.distance.iter_mut().for_each(|d| *d = 0.0);
+    }
+
+    // the raw sums and counts, so a render can be picked up where it was left
+    pub fn save_checkpoint(&self, checkpoint: &Checkpoint, settings: &RenderSettings) -> Result<(), Box<dyn Error>> {
+        // written next to the previous one first, so being killed while saving
+        // still leaves that one intact
+        let path = &checkpoint.path;
+        let temporary = format!("{}.tmp", path);
+        {
+            let mut file = std::io::BufWriter::new(std::fs::File::create(&temporary)?);
+            file.write_all(CHECKPOINT_MAGIC)?;
+            let header = checkpoint.header(settings);
+            file.write_all(&(header.len() as u64).to_le_bytes())?;
+            file.write_all(header.as_bytes())?;
+            for n in [self.width, self.height, self.samples] {
+                file.write_all(&(n as u64).to_le_bytes())?;
+            }
+            for i in 0..self.buffer.len() {
+                file.write_all(&(self.counts[i] as u64).to_le_bytes())?;
+                file.write_all(&self.weights[i].to_le_bytes())?;
+                file.write_all(&self.coverage[i].to_le_bytes())?;
+                file.write_all(&self.distance[i].to_le_bytes())?;
+                for v in [self.buffer[i], self.squares[i], self.albedo[i], self.normals[i], self.direct[i]] {
+                    for c in v.to_array() {
+                        file.write_all(&c.to_le_bytes())?;
+                    }
+                }
+            }
+            file.flush()?;
+        }
+        std::fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    // fails unless the checkpoint was saved with the same settings and scene,
+    // anything else would mix two different images
+    pub fn load_checkpoint(checkpoint: &Checkpoint, settings: &RenderSettings) -> Result<Accumulation, Box<dyn Error>> {
+        fn read_u64(file: &mut impl Read) -> std::io::Result<u64> {
+            let mut bytes = [0; 8];
+            file.read_exact(&mut bytes)?;
+            Ok(u64::from_le_bytes(bytes))
+        }
+
+        fn read_vec3(file: &mut impl Read) -> std::io::Result<Vec3> {
+            let mut c = [0.0; 3];
+            for c in &mut c {
+                let mut bytes = [0; 4];
+                file.read_exact(&mut bytes)?;
+                *c = f32::from_le_bytes(bytes);
+            }
+            Ok(Vec3::new(c[0], c[1], c[2]))
+        }
+
+        let path = &checkpoint.path;
+        let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let mut magic = [0; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != CHECKPOINT_MAGIC {
+            return Err(format!("{} is not a checkpoint", path).into());
+        }
+
+        let length = read_u64(&mut file)?;
+        let mut header = String::new();
+        file.by_ref().take(length).read_to_string(&mut header)?;
+        let expected = checkpoint.header(settings);
+        if header != expected {
+            let differences: Vec<String> = header
+                .lines()
+                .zip(expected.lines())
+                .filter(|(saved, wanted)| saved != wanted)
+                .map(|(saved, wanted)| format!("{} instead of {}", saved, wanted))
+                .collect();
+            return Err(format!("{} can't be resumed, it was saved with {}", path, differences.join(", ")).into());
+        }
+
+        let width = read_u64(&mut file)? as usize;
+        let height = read_u64(&mut file)? as usize;
+        let mut accumulation = Accumulation::try_new(width, height)?;
+        accumulation.samples = read_u64(&mut file)? as usize;
+        for i in 0..width * height {
+            accumulation.counts[i] = read_u64(&mut file)? as usize;
+            let mut bytes = [0; 4];
+            file.read_exact(&mut bytes)?;
+            accumulation.weights[i] = f32::from_le_bytes(bytes);
+            file.read_exact(&mut bytes)?;
+            accumulation.coverage[i] = f32::from_le_bytes(bytes);
+            file.read_exact(&mut bytes)?;
+            accumulation.distance[i] = f32::from_le_bytes(bytes);
+            accumulation.buffer[i] = read_vec3(&mut file)?;
+            accumulation.squares[i] = read_vec3(&mut file)?;
+            accumulation.albedo[i] = read_vec3(&mut file)?;
+            accumulation.normals[i] = read_vec3(&mut file)?;
+            accumulation.direct[i] = read_vec3(&mut file)?;
+        }
+        Ok(accumulation)
+    }
+
+    pub fn average_samples(&self) -> f32 {
+        self.counts.iter().sum::<usize>() as f32 / self.counts.len() as f32
+    }
+
+    fn mean(&self, i: usize) -> Vec3 {
+        // weights are more than zero once any sample hit, and the box filter's
+        // are the sample counts
+        self.buffer[i] / self.weights[i].max(1e-8)
+    }
+
+    // the size of the image the accumulation resolves to
+    fn output_size(&self, settings: &RenderSettings) -> (usize, usize) {
+        let ssaa = settings.ssaa.max(1);
+        (self.width / ssaa, self.height / ssaa)
+    }
+
+    // the averaged colors in buffer order at the output size, filtered when
+    // denoising is on while the accumulated samples stay as they are
+    fn colors(&self, settings: &RenderSettings) -> Vec<Vec3> {
+        let mut colors: Vec<Vec3> = (0..self.buffer.len()).map(|i| self.mean(i)).collect();
+        if settings.denoise {
+            let average = |sums: &[Vec3]| -> Vec<Vec3> {
+                sums.iter().zip(&self.counts).map(|(s, n)| *s / (*n).max(1) as f32).collect()
+            };
+            colors = denoise(&colors, &average(&self.albedo), &average(&self.normals), self.width, self.height);
+        }
+
+        self.downsampled(colors, settings, Vec3::zero())
+    }
+
+    // the covered part of every pixel in buffer order at the output size
+    fn alphas(&self, settings: &RenderSettings) -> Vec<f32> {
+        let alphas = self.coverage.iter().zip(&self.weights).map(|(a, w)| a / w.max(1e-8)).collect();
+        self.downsampled(alphas, settings, 0.0)
+    }
+
+    // supersampled pixels are averaged in blocks while still linear
+    fn downsampled<T>(&self, values: Vec<T>, settings: &RenderSettings, zero: T) -> Vec<T>
+        where T: Copy + std::ops::Add<Output = T> + std::ops::Div<f32, Output = T> {
+        let ssaa = settings.ssaa.max(1);
+        if ssaa == 1 {
+            return values;
+        }
+        let (width, height) = self.output_size(settings);
+        let mut result = vec![zero; width * height];
+        for (i, v) in result.iter_mut().enumerate() {
+            let (x, y) = (i % width * ssaa, i / width * ssaa);
+            for j in 0..ssaa {
+                for k in 0..ssaa {
+                    *v = *v + values[(y + j) * self.width + x + k];
+                }
+            }
+            *v = *v / (ssaa * ssaa) as f32;
+        }
+        result
+    }
+
+    // a pixel has converged when the estimated error of its mean is small next
+    // to its brightness, the offset keeps black pixels from sampling forever
+    fn converged(&self, i: usize, adaptive: &AdaptiveSampling) -> bool {
+        let n = self.counts[i];
+        if n >= adaptive.max_samples {
+            return true;
+        }
+        if n < adaptive.min_samples.max(2) {
+            return false;
+        }
+
+        let mean = self.mean(i);
+        let variance = self.squares[i] / self.weights[i].max(1e-8) - mean * mean;
+        let error = (variance.max_component().max(0.0) / n as f32).sqrt();
+        error <= adaptive.tolerance * (mean.max_component() + 0.01)
+    }
+
+    fn index(&self, x: usize, y: usize) -> usize {
+        (self.height - y - 1) * self.width + x
+    }
+
+    // every row of the buffers at once, in the order they are stored so the
+    // top row comes first, for writing the sums of a pass in parallel
+    fn rows_mut(&mut self) -> impl IndexedParallelIterator<Item = Row<'_>> {
+        let (width, height) = (self.width.max(1), self.height);
+        self.buffer
+            .par_chunks_mut(width)
+            .zip(self.weights.par_chunks_mut(width))
+            .zip(self.counts.par_chunks_mut(width))
+            .zip(self.squares.par_chunks_mut(width))
+            .zip(self.albedo.par_chunks_mut(width))
+            .zip(self.normals.par_chunks_mut(width))
+            .zip(self.coverage.par_chunks_mut(width))
+            .zip(self.direct.par_chunks_mut(width))
+            .zip(self.distance.par_chunks_mut(width))
+            .enumerate()
+            .map(move |(row, ((((((((buffer, weights), counts), squares), albedo), normals), coverage), direct), distance))| {
+                Row { y: height - row - 1, buffer, weights, counts, squares, albedo, normals, coverage, direct, distance }
+            })
+    }
+
+    // writes the average of the accumulated samples to the bitmap
+    pub fn resolve(&self, bitmap: &mut Bitmap, settings: &RenderSettings) {
+        let (width, height) = (bitmap.width, bitmap.height);
+        self.write_pixels(&mut bitmap.buffer, width, height, settings);
+    }
+
+    // the same into a buffer owned by the caller, laid out like a bitmap's with
+    // the top row first and a pixel as 0xAARRGGBB, which has to be the output size
+    pub fn resolve_into(&self, buffer: &mut [u32], width: usize, height: usize, settings: &RenderSettings) -> Result<(), Box<dyn Error>> {
+        if buffer.len() != width * height {
+            return Err(format!("a {}x{} buffer needs {} pixels, not {}", width, height, width * height, buffer.len()).into());
+        }
+        let (output_width, output_height) = self.output_size(settings);
+        if (width, height) != (output_width, output_height) {
+            return Err(format!("the render is {}x{}, not {}x{}", output_width, output_height, width, height).into());
+        }
+        self.write_pixels(buffer, width, height, settings);
+        Ok(())
+    }
+
+    // pixels outside of the buffer are left out
+    fn write_pixels(&self, buffer: &mut [u32], buffer_width: usize, buffer_height: usize, settings: &RenderSettings) {
+        let colors = self.colors(settings);
+        let alphas = if settings.alpha { self.alphas(settings) } else { Vec::new() };
+        let (width, height) = self.output_size(settings);
+        for y in 0..height.min(buffer_height) {
+            for x in 0..width.min(buffer_width) {
+                // the transparent background added nothing, so dividing by the
+                // alpha leaves the color of what covers the pixel
+                let i = (height - y - 1) * width + x;
+                let alpha = alphas.get(i).copied();
+                let c = match alpha {
+                    Some(alpha) if alpha > 0.0 => colors[i] / alpha,
+                    _ => colors[i],
+                };
+
+                // debug modes show their values as they are
+                let c = if settings.mode == RenderMode::Path {
+                    let c = tone_map(c * settings.exposure.exp2(), settings.tone_map);
+                    match settings.transfer {
+                        Transfer::Gamma => gamma_correct(c, settings.gamma).clamp01(),
+                        Transfer::Srgb => srgb_encode(c).clamp01(),
+                    }
+                } else {
+                    c.clamp01()
+                };
+                let r = (c.x * u8::MAX as f32) as u32;
+                let g = (c.y * u8::MAX as f32) as u32;
+                let b = (c.z * u8::MAX as f32) as u32;
+                let p = &mut buffer[(buffer_height - y - 1) * buffer_width + x];
+                let a = match alpha {
+                    Some(alpha) => ((clamped(alpha, 0.0, 1.0) * u8::MAX as f32) as u32) << 24,
+                    None => *p & 0xff000000,
+                };
+                *p = a | r << 16 | g << 8 | b;
+            }
+        }
+    }
+
+    // writes the averaged linear radiance without gamma or clamping, .exr keeps
+    // the values exactly while radiance .hdr files store them with 8 bit mantissas
+    pub fn save_hdr(&self, path: &str, settings: &RenderSettings) -> Result<(), Box<dyn Error>> {
+        self.save_linear(path, self.colors(settings), settings)
+    }
+
+    // the averages of one pass in buffer order at the output size, not denoised
+    fn aov(&self, aov: Aov, settings: &RenderSettings) -> Vec<Vec3> {
+        let per_weight = |sums: &[Vec3]| -> Vec<Vec3> {
+            sums.iter().zip(&self.weights).map(|(s, w)| *s / w.max(1e-8)).collect()
+        };
+        let per_sample = |sums: &[Vec3]| -> Vec<Vec3> {
+            sums.iter().zip(&self.counts).map(|(s, n)| *s / (*n).max(1) as f32).collect()
+        };
+        let values = match aov {
+            Aov::Direct => per_weight(&self.direct),
+            Aov::Indirect => {
+                let indirect: Vec<Vec3> = self.buffer.iter().zip(&self.direct).map(|(c, d)| *c - *d).collect();
+                per_weight(&indirect)
+            }
+            Aov::Albedo => per_sample(&self.albedo),
+            Aov::Normals => per_sample(&self.normals),
+            Aov::Depth => self.distance.iter().zip(&self.coverage).map(|(d, a)| Vec3::splat(d / a.max(1e-8))).collect(),
+        };
+        self.downsampled(values, settings, Vec3::zero())
+    }
+
+    // .exr only, as radiance .hdr files can't hold the negative normals
+    pub fn save_aov(&self, aov: Aov, path: &str, settings: &RenderSettings) -> Result<(), Box<dyn Error>> {
+        if !path.ends_with(".exr") {
+            return Err(format!("{} passes are saved as .exr", aov.name()).into());
+        }
+        self.save_linear(path, self.aov(aov, settings), settings)
+    }
+
+    fn save_linear(&self, path: &str, colors: Vec<Vec3>, settings: &RenderSettings) -> Result<(), Box<dyn Error>> {
+        let pixels: Vec<image::Rgb<f32>> = colors
+            .into_iter()
+            .map(|c| image::Rgb(c.to_array()))
+            .collect();
+        let (width, height) = self.output_size(settings);
+
+        if path.ends_with(".hdr") {
+            let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            image::codecs::hdr::HdrEncoder::new(file).encode(&pixels, width, height)?;
+        } else {
+            let raw = pixels.iter().flat_map(|p| p.0).collect();
+            let image = image::Rgb32FImage::from_raw(width as u32, height as u32, raw)
+                .ok_or("accumulation buffer doesn't match its size")?;
+            image.save_with_format(path, image::ImageFormat::OpenExr)?;
+        }
+        Ok(())
+    }
+}
+
+// edge avoiding a-trous wavelet filter: passes of a 5x5 b-spline kernel with
+// growing gaps between the taps, where neighbours only count as much as their
+// color, normal and albedo are similar to the pixel's
+fn denoise(colors: &[Vec3], albedo: &[Vec3], normals: &[Vec3], width: usize, height: usize) -> Vec<Vec3> {
+    const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+    const PASSES: usize = 5;
+    const NORMAL_PHI: f32 = 0.1;
+    const ALBEDO_PHI: f32 = 0.05;
+
+    let mut current = colors.to_vec();
+    let mut color_phi = 0.1;
+    for pass in 0..PASSES {
+        let step = 1 << pass;
+        current = (0..width * height)
+            .into_par_iter()
+            .map(|i| {
+                let (x, y) = ((i % width) as isize, (i / width) as isize);
+                let mut sum = Vec3::zero();
+                let mut weights = 0.0;
+                for (ky, wy) in KERNEL.iter().enumerate() {
+                    for (kx, wx) in KERNEL.iter().enumerate() {
+                        let qx = x + (kx as isize - 2) * step;
+                        let qy = y + (ky as isize - 2) * step;
+                        if qx < 0 || qy < 0 || qx >= width as isize || qy >= height as isize {
+                            continue;
+                        }
+
+                        let q = qy as usize * width + qx as usize;
+                        let w = wx * wy
+                            * (-(current[q] - current[i]).squared_length() / color_phi).exp()
+                            * (-(normals[q] - normals[i]).squared_length() / NORMAL_PHI).exp()
+                            * (-(albedo[q] - albedo[i]).squared_length() / ALBEDO_PHI).exp();
+                        sum += current[q] * w;
+                        weights += w;
+                    }
+                }
+                sum / weights
+            })
+            .collect();
+
+        // later passes reach further, so they are stricter about color
+        color_phi /= 2.0;
+    }
+    current
+}
+
+// adds the given number of samples per pixel to the accumulation, with adaptive
+// sampling converged pixels are skipped, returns how many pixels were sampled
+// setting cancel stops the pass after the pixels already being rendered, what
+// was done until then is still added to the accumulation
+pub fn render(accumulation: &mut Accumulation, world: &World, camera: &Camera, settings: &RenderSettings, samples: usize, cancel: &AtomicBool) -> usize {
+    let width = accumulation.width;
+    let height = accumulation.height;
+    let seed = settings.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    let pixels_sampled = AtomicUsize::new(0);
+
+    let render_pixel = |x: usize, y: usize| {
+        let i = accumulation.index(x, y);
+        let first_sample = accumulation.counts[i];
+        let samples = match &settings.adaptive {
+            Some(adaptive) if accumulation.converged(i, adaptive) => 0,
+            Some(adaptive) => samples.min(adaptive.max_samples - first_sample),
+            None => samples,
+        };
+        if samples > 0 {
+            pixels_sampled.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // the samples are added on to the sums so far in the same order however
+        // they are split into passes, which keeps the result the same to the bit
+        let mut sum = Sample {
+            color: accumulation.buffer[i],
+            albedo: accumulation.albedo[i],
+            normal: accumulation.normals[i],
+            bounces: 0,
+            coverage: accumulation.coverage[i],
+            direct: accumulation.direct[i],
+            distance: accumulation.distance[i],
+        };
+        let mut squares = accumulation.squares[i];
+        let mut weights = accumulation.weights[i];
+
+        // stratified sampling jitters one sample inside every cell of an n by n
+        // grid over the pixel, which needs a square number of samples; the
+        // cells of a grid over all the samples a pixel gets are visited in a
+        // shuffled order across the passes, adaptive passes get one each
+        let square = |n: usize| Some((n as f64).sqrt().round() as usize).filter(|grid| grid * grid == n);
+        let pixel_seed = seed ^ ((y * width + x) as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        let cell = |index: usize, sample: usize| match &settings.adaptive {
+            _ if !settings.stratified => None,
+            Some(_) => square(samples).map(|grid| (sample, grid)),
+            None => square(settings.samples_per_pixel).filter(|_| index < settings.samples_per_pixel).map(|grid| {
+                let shuffled = permute(index as u32, settings.samples_per_pixel as u32, (pixel_seed ^ pixel_seed >> 32) as u32);
+                (shuffled as usize, grid)
+            }),
+        };
+
+        for sample in 0..samples {
+            // every sample of every pixel gets its own generator, so the result
+            // doesn't depend on which thread renders it, in what order, or in
+            // how many passes
+            let index = first_sample + sample;
+            let mut random = SampleRng::new(pixel_seed ^ (index as u64).wrapping_mul(0xbf58_476d_1ce4_e5b9));
+            let (jitter_x, jitter_y) = match cell(index, sample) {
+                Some((cell, grid)) => (((cell % grid) as f32 + random.gen_range(0.0, 1.0)) / grid as f32,
+                                       ((cell / grid) as f32 + random.gen_range(0.0, 1.0)) / grid as f32),
+                None => (random.gen_range(0.0, 1.0), random.gen_range(0.0, 1.0)),
+            };
+            let (offset_x, offset_y, weight) = if settings.fast_aa {
+                (0.5, 0.5, 1.0)
+            } else {
+                settings.filter.sample(jitter_x, jitter_y)
+            };
+            let x_scaled = ((x as f32) + offset_x) / (width as f32);
+            let y_scaled = ((y as f32) + offset_y) / (height as f32);
+            let ray = camera.ray(x_scaled, y_scaled, &mut random);
+            let sample = if settings.fast_aa {
+                fast_aa_color(ray, world, camera, settings, width, &mut random)
+            } else {
+                color(ray, world, settings, &mut random)
+            };
+            sum.color += weight * sample.color;
+            weights += weight;
+            sum.albedo += sample.albedo;
+            sum.normal += sample.normal;
+            sum.bounces += sample.bounces;
+            sum.coverage += weight * sample.coverage;
+            sum.direct += weight * sample.direct;
+            sum.distance += weight * sample.coverage * sample.distance;
+            squares += weight * sample.color * sample.color;
+        }
+        STATS.record_paths(samples, sum.bounces);
+
+        (sum, weights, squares, first_sample + samples)
+    };
+
+    let mut tiles = tiles(width, height, settings.tile_size);
+    if let Some(region) = &settings.region {
+        let region = region.tile(width, height, settings.ssaa.max(1));
+        tiles = tiles.iter().filter_map(|tile| tile.intersection(&region)).collect();
+    }
+    let tiles_done = AtomicUsize::new(0);
+    let last_report = Mutex::new(Instant::now());
+    let reported = AtomicBool::new(false);
+    // verbose output lists the tiles instead
+    let report_progress = |done: usize| {
+        if settings.verbosity == 1 {
+            let percent = done * 100 / tiles.len();
+            eprint!("\rRendering... {:3}%", percent);
+        }
+    };
+
+    let rendered: Vec<_> = tiles
+        .par_iter()
+        .map(|tile| {
+            let tile_start = Instant::now();
+            let mut pixels = Vec::with_capacity(tile.width * tile.height);
+            'tile: for y in tile.y..tile.y + tile.height {
+                for x in tile.x..tile.x + tile.width {
+                    if cancel.load(Ordering::Relaxed) {
+                        break 'tile;
+                    }
+                    pixels.push(render_pixel(x, y));
+                }
+            }
+
+            detail!(settings, "Tile {}x{} at {}, {} took {} ms", tile.width, tile.height, tile.x, tile.y, tile_start.elapsed().as_millis());
+
+            // only print every so often, busy threads would spam stderr otherwise,
+            // and passes that finish quickly don't print at all
+            let done = tiles_done.fetch_add(1, Ordering::Relaxed) + 1;
+            if let Ok(mut last_report) = last_report.try_lock() {
+                if last_report.elapsed() >= Duration::from_millis(200) {
+                    report_progress(done);
+                    reported.store(true, Ordering::Relaxed);
+                    *last_report = Instant::now();
+                }
+            }
+
+            (tile, pixels)
+        })
+        .collect();
+
+    let cancelled = cancel.load(Ordering::Relaxed);
+    if reported.load(Ordering::Relaxed) {
+        if !cancelled {
+            report_progress(tiles.len());
+        }
+        if settings.verbosity == 1 {
+            eprintln!();
+        }
+    }
+
+    // the rows are written in parallel, each from the tiles in its band of the
+    // grid, which a cancelled pass only filled the first pixels of
+    let tile_size = settings.tile_size;
+    let columns = width.div_ceil(tile_size);
+    let mut grid = vec![None; columns * height.div_ceil(tile_size)];
+    for (tile, pixels) in &rendered {
+        grid[tile.y / tile_size * columns + tile.x / tile_size] = Some((*tile, pixels));
+    }
+    accumulation.rows_mut().for_each(|mut row| {
+        let band = row.y / tile_size;
+        for (tile, pixels) in grid[band * columns..(band + 1) * columns].iter().flatten() {
+            if row.y < tile.y || row.y >= tile.y + tile.height {
+                continue;
+            }
+            let start = (row.y - tile.y) * tile.width;
+            for (dx, pixel) in pixels.iter().skip(start).take(tile.width).enumerate() {
+                row.set(tile.x + dx, pixel);
+            }
+        }
+    });
+    // a cancelled pass only reached some of the pixels, which keep their own counts
+    if !cancelled {
+        accumulation.samples += samples;
+    }
+
+    pixels_sampled.into_inner()
+}
+
+// where and how often render_to_completion saves what it has so far
+pub struct Checkpoint {
+    pub path: String,
+    pub interval: Duration,
+    // a hash of the scene, which a checkpoint has to be resumed with
+    pub scene: u64,
+}
+
+impl Checkpoint {
+    // what the samples in a checkpoint depend on, one per line
+    fn header(&self, settings: &RenderSettings) -> String {
+        let seed = settings.seed.map_or("random".to_string(), |seed| seed.to_string());
+        let adaptive = settings.adaptive.map_or("none".to_string(), |adaptive| {
+            format!("{} to {} at {}", adaptive.min_samples, adaptive.max_samples, adaptive.tolerance)
+        });
+        format!("seed {}\nsamples {}\nadaptive {}\nssaa {}\nfilter {:?}\nbounces {}\nstratified {}\nscene {:016x}\n",
+                seed, settings.samples_per_pixel, adaptive, settings.ssaa, settings.filter, settings.max_bounces, settings.stratified,
+                self.scene)
+    }
+}
+
+// the samples of a pass when checkpointing, a checkpoint can only be saved
+// between passes; the image comes out the same with passes of any size
+const CHECKPOINT_BATCH: usize = 8;
+
+// renders all samples at once, with adaptive sampling in batches of the minimum
+// sample count until every pixel has converged
+pub fn render_to_completion(accumulation: &mut Accumulation, world: &World, camera: &Camera, settings: &RenderSettings,
+                            checkpoint: Option<&Checkpoint>) -> Result<(), Box<dyn Error>> {
+    // with a time budget the passes are single samples, so the image stays even
+    // when the one that runs out of time is cut short
+    let batch = match (&settings.adaptive, checkpoint) {
+        _ if settings.time_budget_ms.is_some() || settings.fast_aa => 1,
+        (Some(adaptive), _) => adaptive.min_samples,
+        (None, Some(_)) => CHECKPOINT_BATCH,
+        (None, None) => settings.samples_per_pixel,
+    };
+
+    // a timer thread cancels the pass in progress once the budget is used up,
+    // the workers only ever look at the flag so they don't wait on each other
+    let cancel = AtomicBool::new(false);
+    let finished = AtomicBool::new(false);
+    let never = AtomicBool::new(false);
+    let deadline = settings.time_budget_ms.map(|ms| Instant::now() + Duration::from_millis(ms));
+    std::thread::scope(|scope| {
+        if let Some(deadline) = deadline {
+            let (finished, cancel) = (&finished, &cancel);
+            scope.spawn(move || {
+                while !finished.load(Ordering::Relaxed) && Instant::now() < deadline {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                cancel.store(true, Ordering::Relaxed);
+            });
+        }
+
+        let mut last_save = Instant::now();
+        let mut result = Ok(());
+        while accumulation.samples() < settings.target_samples() {
+            let first = accumulation.samples() == 0;
+            if !first && cancel.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let samples = batch.min(settings.target_samples() - accumulation.samples());
+            // adaptive renders are done early once every pixel has converged
+            let sampled = render(accumulation, world, camera, settings, samples, if first { &never } else { &cancel });
+            detail!(settings, "Pass of {} samples per pixel took {} pixels to {} samples", samples, sampled, accumulation.samples());
+            if let Some(checkpoint) = checkpoint {
+                if last_save.elapsed() >= checkpoint.interval {
+                    result = accumulation.save_checkpoint(checkpoint, settings);
+                    if result.is_err() {
+                        break;
+                    }
+                    last_save = Instant::now();
+                }
+            }
+            if sampled == 0 {
+                break;
+            }
+        }
+        finished.store(true, Ordering::Relaxed);
+        result
+    })?;
+
+    // the finished render is saved too, resuming it then has nothing left to do
+    if let Some(checkpoint) = checkpoint {
+        accumulation.save_checkpoint(checkpoint, settings)?;
+    }
+    Ok(())
+}
+
+// renders a scene from start to finish straight into a buffer owned by the
+// caller, like the texture of a gui, laid out as Accumulation::resolve_into
+// describes; the scene's own settings are ignored for the ones given
+pub fn render_into(buffer: &mut [u32], width: usize, height: usize, settings: &RenderSettings, scene: Scene) -> Result<(), Box<dyn Error>> {
+    if buffer.len() != width * height {
+        return Err(format!("a {}x{} buffer needs {} pixels, not {}", width, height, width * height, buffer.len()).into());
+    }
+    scene.validate()?;
+    let camera = scene.camera.camera(width as f32 / height as f32);
+    let world = scene.world(false, BvhBuild::default())?;
+    let ssaa = settings.ssaa.max(1);
+    let mut accumulation = Accumulation::try_new(width * ssaa, height * ssaa)?;
+    render_to_completion(&mut accumulation, &world, &camera, settings, None)?;
+    accumulation.resolve_into(buffer, width, height, settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use crate::geometry::{RectXY, RectXZ, Sphere};
+    use crate::material::{Material, Texture};
+    use crate::scene::{CameraSettings, PointLight, default_scene};
+
+    fn lambertian(albedo: f32) -> Material {
+        Material::Lambertian { albedo: Texture::Solid(Vec3::splat(albedo)) }
+    }
+
+    // the mean and the variance of the red channel of paths along the rays
+    fn estimate(world: &World, settings: &RenderSettings, rays: impl Fn(&mut StdRng) -> Ray, paths: usize, seed: u64) -> (f64, f64) {
+        let mut random = StdRng::seed_from_u64(seed);
+        let (mut sum, mut squares) = (0.0, 0.0);
+        for _ in 0..paths {
+            let ray = rays(&mut random);
+            let red = color(ray, world, settings, &mut random).color.x as f64;
+            sum += red;
+            squares += red * red;
+        }
+        let mean = sum / paths as f64;
+        (mean, squares / paths as f64 - mean * mean)
+    }
+
+    // a gray floor under a small round light, seen straight down from below the
+    // light so that the first bounce is all there is to it
+    fn light_above_floor(floor: Material) -> World {
+        let mut scene = Scene::new(default_scene().camera);
+        scene.rects_xz.push(RectXZ { x0: -100.0, x1: 100.0, z0: -100.0, z1: 100.0, k: 0.0, material: floor, flip: false });
+        let light = Material::Emissive { emit: Vec3::splat(4.0), two_sided: false };
+        scene.spheres.push(Sphere::new(Vec3::new(0.0, 2.0, 0.0), 0.5, light));
+        scene.world(false, BvhBuild::Median).unwrap()
+    }
+
+    fn direct_only() -> RenderSettings {
+        RenderSettings { max_bounces: 1, environment: Environment::Solid(Vec3::zero()), ..RenderSettings::default() }
+    }
+
+    #[test]
+    fn ppm_is_written_top_row_first() {
+        let mut bitmap = Bitmap::new(2, 2);
+        // y counts up from the bottom, the file starts at the top
+        *bitmap.get_mut(0, 1).unwrap() = 0xffff0000;
+        *bitmap.get_mut(1, 1).unwrap() = 0xff00ff00;
+        *bitmap.get_mut(0, 0).unwrap() = 0xff0000ff;
+        *bitmap.get_mut(1, 0).unwrap() = 0xff102030;
+        let mut bytes = Vec::new();
+        bitmap.write_ppm(&mut bytes).unwrap();
+
+        let mut expected = b"P6\n2 2\n255\n".to_vec();
+        expected.extend_from_slice(&[255, 0, 0, 0, 255, 0, 0, 0, 255, 0x10, 0x20, 0x30]);
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn tiles_cover_every_pixel_once() {
+        for &(width, height, tile_size) in &[(37, 23, 8), (100, 3, 16), (5, 5, 32), (33, 65, 4), (48, 48, 16)] {
+            let tiles = tiles(width, height, tile_size);
+            assert_eq!(tiles.len(), width.div_ceil(tile_size) * height.div_ceil(tile_size));
+            assert_eq!((tiles[0].x, tiles[0].y), (0, 0));
+            let mut covered = vec![0; width * height];
+            for tile in &tiles {
+                for y in tile.y..tile.y + tile.height {
+                    for x in tile.x..tile.x + tile.width {
+                        covered[y * width + x] += 1;
+                    }
+                }
+            }
+            assert!(covered.iter().all(|&count| count == 1), "{}x{} in tiles of {}", width, height, tile_size);
+        }
+    }
+
+    #[test]
+    fn sample_generators_are_uniform_and_differ_by_seed() {
+        let mut random = SampleRng::new(0);
+        let mean = (0..100_000).map(|_| random.gen::<f64>()).sum::<f64>() / 100_000.0;
+        assert!((mean - 0.5).abs() < 0.005, "{}", mean);
+        let first = |seed| SampleRng::new(seed).next_u64();
+        assert_eq!(first(1), first(1));
+        assert_ne!(first(1), first(2));
+    }
+
+    #[test]
+    fn permute_shuffles_every_cell_once() {
+        for &(n, seed) in &[(1, 0), (16, 0), (16, 12345), (100, 7), (1000, 0xdead_beef)] {
+            let mut seen = vec![false; n as usize];
+            for i in 0..n {
+                let cell = permute(i, n, seed);
+                assert!(!seen[cell as usize], "{} twice in {} cells", cell, n);
+                seen[cell as usize] = true;
+            }
+        }
+        assert_ne!((0..16).map(|i| permute(i, 16, 1)).collect::<Vec<_>>(), (0..16).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn convergence_uses_the_filter_weights() {
+        let adaptive = AdaptiveSampling { min_samples: 2, max_samples: 64, tolerance: 0.01 };
+        let mut accumulation = Accumulation::new(1, 1);
+        // two samples of 1 at full weight and one of 3 at the filter's edge,
+        // which barely counts toward the mean and shouldn't toward the variance
+        let samples = [(Vec3::splat(1.0), 1.0), (Vec3::splat(1.0), 1.0), (Vec3::splat(3.0), 1e-6)];
+        for (color, weight) in samples {
+            accumulation.buffer[0] += weight * color;
+            accumulation.weights[0] += weight;
+            accumulation.squares[0] += weight * color * color;
+            accumulation.counts[0] += 1;
+        }
+        assert!(accumulation.converged(0, &adaptive));
+
+        // samples of 0 and 2 are noise however little weight they all have
+        let mut accumulation = Accumulation::new(1, 1);
+        for sample in 0..16 {
+            let (color, weight) = (Vec3::splat((sample % 2 * 2) as f32), 0.25);
+            accumulation.buffer[0] += weight * color;
+            accumulation.weights[0] += weight;
+            accumulation.squares[0] += weight * color * color;
+            accumulation.counts[0] += 1;
+        }
+        assert!(!accumulation.converged(0, &adaptive));
+    }
+
+    #[test]
+    fn impossible_sizes_are_errors() {
+        assert!(zeroed(0u8, usize::MAX).is_err());
+        assert!(zeroed(Vec3::zero(), usize::MAX / 4).is_err());
+        // the pixel count itself overflows
+        assert!(Bitmap::try_new(usize::MAX, 2).is_err());
+        assert!(Accumulation::try_new(1 << 40, 1 << 40).is_err());
+        // the pixels fit in a usize, the bytes of their buffers don't
+        assert!(Accumulation::try_new(1 << 31, 1 << 31).is_err());
+        assert!(Accumulation::memory_needed(1 << 31, 1 << 31).is_none());
+        assert!(zeroed(0u8, 16).is_ok_and(|v| v.len() == 16));
+    }
+
+    #[test]
+    fn russian_roulette_keeps_the_mean() {
+        // inside a closed sphere of albedo a with a point light in the middle,
+        // every bounce sees a times the light of the one before, a / (1 - a) in all
+        let mut scene = Scene::new(default_scene().camera);
+        scene.spheres.push(Sphere::new(Vec3::zero(), 1.0, lambertian(0.8)));
+        scene.point_lights.push(PointLight { position: Vec3::zero(), intensity: Vec3::splat(1.0) });
+        let world = scene.world(false, BvhBuild::Median).unwrap();
+        let expected = 0.8 / 0.2;
+        let rays = |random: &mut StdRng| Ray::new(Vec3::zero(), Vec3::random_in_unit_sphere(random));
+
+        let full = RenderSettings { max_bounces: 200, russian_roulette: false, ..RenderSettings::default() };
+        let (mean, variance) = estimate(&world, &full, rays, 2000, 31);
+        assert!((mean - expected).abs() < 1e-3, "{} without roulette", mean);
+        assert!(variance < 1e-6);
+
+        let roulette = RenderSettings { russian_roulette: true, ..full };
+        let (mean, variance) = estimate(&world, &roulette, rays, 20000, 31);
+        let error = (variance / 20000.0).sqrt();
+        assert!((mean - expected).abs() < 4.0 * error, "{} with roulette, {} expected", mean, expected);
+        assert!(error < 0.05);
+    }
+
+    #[test]
+    fn stratified_pixels_vary_less_across_an_edge() {
+        // a single pixel looking straight at the edge of a light halfway across it
+        let mut scene = Scene::new(CameraSettings {
+            projection: Projection::Orthographic { scale: 2.0 },
+            origin: Vec3::zero(),
+            look_at: Vec3::new(0.0, 0.0, -1.0),
+            ..default_scene().camera
+        });
+        let light = Material::Emissive { emit: Vec3::splat(1.0), two_sided: true };
+        scene.rects_xy.push(RectXY { x0: 0.1, x1: 10.0, y0: -10.0, y1: 10.0, k: -5.0, material: light, flip: false });
+        let camera = scene.camera.camera(1.0);
+        let world = scene.world(false, BvhBuild::Median).unwrap();
+
+        let variance = |stratified: bool| {
+            let (mut sum, mut squares) = (0.0, 0.0);
+            for seed in 0..400 {
+                let settings = RenderSettings {
+                    samples_per_pixel: 16,
+                    seed: Some(seed),
+                    stratified,
+                    environment: Environment::Solid(Vec3::zero()),
+                    ..RenderSettings::default()
+                };
+                let mut accumulation = Accumulation::new(1, 1);
+                render(&mut accumulation, &world, &camera, &settings, 16, &AtomicBool::new(false));
+                let red = accumulation.mean(0).x as f64;
+                sum += red;
+                squares += red * red;
+            }
+            let mean = sum / 400.0;
+            assert!((mean - 0.45).abs() < 0.02, "mean {} with stratified {}", mean, stratified);
+            squares / 400.0 - mean * mean
+        };
+        let (random, stratified) = (variance(false), variance(true));
+        // about 0.45 * 0.55 / 16 for independent samples
+        assert!(random > 0.01, "{}", random);
+        assert!(stratified < random / 4.0, "stratified {} against {}", stratified, random);
+    }
+
+    #[test]
+    fn light_sampling_keeps_the_mean() {
+        // a sphere seen at half angle asin(r / d) gives a floor right under it
+        // albedo * emit * (r / d)^2
+        let expected = 0.5 * 4.0 * 0.25 * 0.25;
+        let rays = |_: &mut StdRng| Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let world = light_above_floor(lambertian(0.5));
+        assert_eq!(world.lights.len(), 1);
+
+        let (mean, variance) = estimate(&world, &direct_only(), rays, 4000, 56);
+        let error = (variance / 4000.0).sqrt();
+        assert!((mean - expected).abs() < 4.0 * error, "{} sampling the light, {} expected", mean, expected);
+
+        // without the light in the list only the material can find it
+        let mut world = world;
+        world.lights.clear();
+        let (mean, variance) = estimate(&world, &direct_only(), rays, 40000, 56);
+        let error = (variance / 40000.0).sqrt();
+        assert!((mean - expected).abs() < 4.0 * error, "{} without light sampling, {} expected", mean, expected);
+    }
+
+    #[test]
+    fn light_sampling_is_less_noisy() {
+        let rays = |_: &mut StdRng| Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        // the heuristic should help on the diffuse floor by a lot and on the glossy
+        // one, where the material alone already finds the light often, by less
+        let glossy = Material::Phong { diffuse: Vec3::splat(0.2), specular: Vec3::splat(0.5), shininess: 50.0 };
+        for (floor, factor) in [(lambertian(0.5), 100.0), (glossy, 2.0)] {
+            let mut world = light_above_floor(floor);
+            let (_, combined) = estimate(&world, &direct_only(), rays, 4000, 69);
+            world.lights.clear();
+            let (_, material) = estimate(&world, &direct_only(), rays, 4000, 69);
+            assert!(combined * factor < material, "{} with the lights sampled, {} without", combined, material);
+        }
+    }
+
+    #[test]
+    fn a_world_renders_the_same_every_frame() {
+        // the world and its tree are built once and only borrowed by the passes,
+        // so later frames match the first one and one on a freshly built world
+        let camera = default_scene().camera.camera(4.0 / 3.0);
+        let settings = RenderSettings { seed: Some(50), verbosity: 0, ..RenderSettings::default() };
+        let frame = |world: &World| {
+            let mut accumulation = Accumulation::new(16, 12);
+            render(&mut accumulation, world, &camera, &settings, 2, &AtomicBool::new(false));
+            accumulation.buffer
+        };
+        let world = default_scene().world(false, BvhBuild::Sah).unwrap();
+        let first = frame(&world);
+        assert!(first.iter().any(|color| !color.near_zero()));
+        for _ in 0..3 {
+            assert_eq!(frame(&world), first);
+        }
+        assert_eq!(frame(&default_scene().world(false, BvhBuild::Sah).unwrap()), first);
+    }
+
+    #[test]
+    fn resumed_renders_match_uninterrupted_ones() {
+        let scene = default_scene();
+        let camera = scene.camera.camera(4.0 / 3.0);
+        let world = scene.world(false, BvhBuild::Sah).unwrap();
+        let never = AtomicBool::new(false);
+        let directory = std::env::temp_dir();
+        let checkpoint = |name: &str| Checkpoint {
+            path: directory.join(format!("kektracer-{}-{}", std::process::id(), name)).to_string_lossy().into_owned(),
+            interval: Duration::from_secs(0),
+            scene: 1,
+        };
+        let bytes = |accumulation: &Accumulation, settings: &RenderSettings, name: &str| {
+            let checkpoint = checkpoint(name);
+            accumulation.save_checkpoint(&checkpoint, settings).unwrap();
+            let bytes = std::fs::read(&checkpoint.path).unwrap();
+            std::fs::remove_file(&checkpoint.path).unwrap();
+            bytes
+        };
+
+        for stratified in [false, true] {
+            let settings = RenderSettings { samples_per_pixel: 16, seed: Some(61), stratified, verbosity: 0, ..RenderSettings::default() };
+            let mut whole = Accumulation::new(20, 15);
+            render(&mut whole, &world, &camera, &settings, 16, &never);
+
+            // 5 samples, saved and loaded again, then the other 11 in two passes
+            let mut part = Accumulation::new(20, 15);
+            render(&mut part, &world, &camera, &settings, 5, &never);
+            let saved = checkpoint("part");
+            part.save_checkpoint(&saved, &settings).unwrap();
+            let mut resumed = Accumulation::load_checkpoint(&saved, &settings).unwrap();
+            std::fs::remove_file(&saved.path).unwrap();
+            render(&mut resumed, &world, &camera, &settings, 3, &never);
+            render(&mut resumed, &world, &camera, &settings, 8, &never);
+            assert!(bytes(&resumed, &settings, "resumed") == bytes(&whole, &settings, "whole"), "stratified {}", stratified);
+
+            // and the batches a checkpointed render_to_completion takes
+            let saved = checkpoint("batches");
+            let mut batches = Accumulation::new(20, 15);
+            render_to_completion(&mut batches, &world, &camera, &settings, Some(&saved)).unwrap();
+            std::fs::remove_file(&saved.path).unwrap();
+            assert!(bytes(&batches, &settings, "batches") == bytes(&whole, &settings, "whole"), "stratified {}", stratified);
+        }
+    }
+
+    #[test]
+    fn checkpoints_only_resume_with_the_same_settings_and_scene() {
+        let path = std::env::temp_dir().join(format!("kektracer-{}-settings", std::process::id())).to_string_lossy().into_owned();
+        let checkpoint = Checkpoint { path: path.clone(), interval: Duration::from_secs(0), scene: 1 };
+        let settings = RenderSettings { samples_per_pixel: 16, seed: Some(1), ..RenderSettings::default() };
+        Accumulation::new(4, 3).save_checkpoint(&checkpoint, &settings).unwrap();
+        assert!(Accumulation::load_checkpoint(&checkpoint, &settings).is_ok());
+
+        let adaptive = AdaptiveSampling { min_samples: 4, max_samples: 64, tolerance: 0.01 };
+        let changes = [
+            ("seed", RenderSettings { seed: Some(99), ..settings.clone() }),
+            ("seed", RenderSettings { seed: None, ..settings.clone() }),
+            ("samples", RenderSettings { samples_per_pixel: 32, ..settings.clone() }),
+            ("adaptive", RenderSettings { adaptive: Some(adaptive), ..settings.clone() }),
+            ("ssaa", RenderSettings { ssaa: 2, ..settings.clone() }),
+            ("filter", RenderSettings { filter: Filter::Gaussian, ..settings.clone() }),
+            ("bounces", RenderSettings { max_bounces: 5, ..settings.clone() }),
+            ("stratified", RenderSettings { stratified: true, ..settings.clone() }),
+        ];
+        for (name, changed) in &changes {
+            let error = Accumulation::load_checkpoint(&checkpoint, changed).err().expect(name).to_string();
+            assert!(error.contains(name), "{}", error);
+        }
+        let other_scene = Checkpoint { scene: 2, ..checkpoint };
+        let error = Accumulation::load_checkpoint(&other_scene, &settings).err().expect("scene").to_string();
+        assert!(error.contains("scene 0000000000000001 instead of scene 0000000000000002"), "{}", error);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,887 @@
+use std::error::Error;
+use rand::{Rng, RngCore, SeedableRng};
+use rand::rngs::StdRng;
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+use crate::clamped;
+use crate::geometry::{Vec3, Ray, Hit, Aabb, Hittable, Sphere, SpherePacket, Plane, Triangle, RectXY, RectXZ, RectYZ, Cuboid, Cylinder, Disk,
+                      Translate, RotateY, Mesh, Medium};
+use crate::material::{ImageTexture, Texture, Material};
+use crate::bvh::{BvhNode, BvhBuild};
+use crate::render::RenderSettings;
+
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct PointLight {
+    pub position: Vec3,
+    pub intensity: Vec3,
+}
+
+// a point light that only shines into a cone around direction, fading out
+// between the inner and the outer angle, both in degrees from the axis
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct SpotLight {
+    pub position: Vec3,
+    pub direction: Vec3,
+    pub intensity: Vec3,
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+}
+
+impl SpotLight {
+    // how much of the intensity goes out toward a point, 1 inside the inner
+    // cone and smoothly down to 0 at the outer one
+    fn falloff(&self, point: Vec3) -> f32 {
+        let cosine = (point - self.position).unit_vector().dot(self.direction.unit_vector());
+        let cos_inner = self.inner_angle.to_radians().cos();
+        let cos_outer = self.outer_angle.to_radians().cos();
+        if cos_inner <= cos_outer {
+            return if cosine >= cos_outer { 1.0 } else { 0.0 };
+        }
+        let x = clamped((cosine - cos_outer) / (cos_inner - cos_outer), 0.0, 1.0);
+        x * x * (3.0 - 2.0 * x)
+    }
+}
+
+pub struct World {
+    objects: Vec<Box<dyn Hittable>>,
+    // copies of the emissive objects, which diffuse bounces are steered toward
+    pub(crate) lights: Vec<Box<dyn Hittable>>,
+    point_lights: Vec<PointLight>,
+    spot_lights: Vec<SpotLight>,
+    // the boxes of the objects as given in the scene, for the overlay
+    pub(crate) bounds: Vec<Aabb>,
+    // the centers and radii of the spheres, whose outlines fast_aa smooths
+    pub(crate) silhouettes: Vec<(Vec3, f32)>,
+    // what went wrong in loading the scene without stopping it, for the
+    // caller to show
+    pub warnings: Vec<String>,
+}
+
+impl World {
+    // what the world was built from, for --verbose
+    pub fn summary(&self) -> String {
+        format!("{} top level objects, {} sampled lights, {} point lights, {} spot lights",
+                self.objects.len(), self.lights.len(), self.point_lights.len(), self.spot_lights.len())
+    }
+
+    // everything with a bounding box goes into a bvh, which World then
+    // tests like any other object
+    fn new(objects: Vec<Box<dyn Hittable>>, lights: Vec<Box<dyn Hittable>>, point_lights: Vec<PointLight>, spot_lights: Vec<SpotLight>,
+           bounds: Vec<Aabb>, silhouettes: Vec<(Vec3, f32)>, build: BvhBuild) -> World {
+        let (bounded, mut objects): (Vec<_>, Vec<_>) = objects
+            .into_iter()
+            .partition(|o| o.bounding_box().is_some());
+        if !bounded.is_empty() {
+            objects.push(BvhNode::build(bounded, build));
+        }
+
+        World { objects, lights, point_lights, spot_lights, bounds, silhouettes, warnings: Vec::new() }
+    }
+
+    // light arriving at a point straight from the lights that aren't occluded,
+    // weighted by the cosine to the normal and falling off with distance squared
+    pub(crate) fn direct_lighting(&self, position: Vec3, normal: Vec3, epsilon: f32, random: &mut dyn RngCore) -> Vec3 {
+        // spot lights are point lights dimmed by their cone, and skipped when the
+        // point is outside of it
+        let point_lights = self.point_lights.iter().map(|l| (l.position, l.intensity));
+        let spot_lights = self.spot_lights
+            .iter()
+            .map(|l| (l.position, l.intensity * l.falloff(position)))
+            .filter(|(_, intensity)| !intensity.near_zero());
+
+        let mut result = Vec3::zero();
+        for (light_position, intensity) in point_lights.chain(spot_lights) {
+            let to_light = light_position - position;
+            let distance = to_light.length();
+            let direction = to_light / distance;
+            let cosine = normal.dot(direction);
+            if cosine <= 0.0 {
+                continue;
+            }
+
+            let shadow_ray = Ray::new(position, direction);
+            if self.hit_test(&shadow_ray, epsilon, distance, random).is_none() {
+                result += intensity * cosine / (distance * distance);
+            }
+        }
+        result
+    }
+
+    pub(crate) fn hit_test(&self, ray: &Ray, t_min: f32, t_max: f32, random: &mut dyn RngCore) -> Option<Hit<'_>> {
+        let mut closest_t = t_max;
+        let mut result = None;
+
+        self.objects.iter().for_each(|o| {
+            if let Some(hit) = o.hit_test(ray, t_min, closest_t, random) {
+                closest_t = hit.t;
+                result = Some(hit);
+            }
+        });
+
+        result
+    }
+
+    pub(crate) fn nodes_visited(&self, ray: &Ray, t_min: f32, t_max: f32, random: &mut dyn RngCore) -> usize {
+        let mut closest_t = t_max;
+        let mut nodes = 0;
+        for o in &self.objects {
+            let (visited, t) = o.nodes_visited(ray, t_min, closest_t, random);
+            nodes += visited;
+            closest_t = t.unwrap_or(closest_t);
+        }
+        nodes
+    }
+}
+
+// how rays leave the camera, given in scene files as
+// projection = { type = "orthographic", scale = 2.0 }
+#[derive(Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "lowercase"))]
+pub enum Projection {
+    #[default]
+    Perspective,
+    // parallel rays, scale is the height of the visible area in world units
+    Orthographic { scale: f32 },
+    // a full 360 degree panorama around the origin, meant for a 2:1 image
+    Equirectangular,
+}
+
+pub struct Camera {
+    pub(crate) projection: Projection,
+    origin: Vec3,
+    lower_left_corner: Vec3,
+    horizontal: Vec3,
+    vertical: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    lens_radius: f32,
+    aperture_blades: u32,
+}
+
+impl Camera {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(projection: Projection, origin: Vec3, look_at: Vec3, up: Vec3, roll: f32, vertical_fov: f32, aspect_ratio: f32, aperture: f32, aperture_blades: u32, focus_dist: f32) -> Camera {
+        // create orthonormal basis
+        let w = (origin - look_at).unit_vector();
+        let u = up.cross(w).unit_vector();
+        let v = w.cross(u);
+
+        // rolled around w, left as is without a roll so nothing moves by rounding
+        let (u, v) = if roll != 0.0 {
+            let (sin, cos) = roll.to_radians().sin_cos();
+            (cos * u + sin * v, cos * v - sin * u)
+        } else {
+            (u, v)
+        };
+
+        let (lower_left_corner, horizontal, vertical) = match projection {
+            Projection::Perspective => {
+                let half_height = (vertical_fov.to_radians() / 2.0).tan();
+                let half_width = aspect_ratio * half_height;
+
+                // the image plane is placed at the focus distance, so that is where rays from
+                // different points on the lens converge
+                (origin - focus_dist * (half_width * u + half_height * v + w),
+                 2.0 * focus_dist * half_width * u,
+                 2.0 * focus_dist * half_height * v)
+            }
+            Projection::Orthographic { scale } => {
+                // rays start on a plane through the origin and all point along -w
+                let half_height = scale / 2.0;
+                let half_width = aspect_ratio * half_height;
+                (origin - half_width * u - half_height * v, 2.0 * half_width * u, 2.0 * half_height * v)
+            }
+            // directions are computed from the basis alone
+            Projection::Equirectangular => (origin, Vec3::zero(), Vec3::zero()),
+        };
+
+        Camera {
+            projection,
+            lower_left_corner,
+            horizontal,
+            vertical,
+            lens_radius: aperture / 2.0,
+            aperture_blades,
+            origin,
+            u,
+            v,
+            w,
+        }
+    }
+
+    pub fn ray(&self, u: f32, v: f32, random: &mut impl Rng) -> Ray {
+        match self.projection {
+            Projection::Perspective => {
+                let lens = self.lens_radius * Vec3::random_in_polygon(self.aperture_blades, random);
+                let origin = self.origin + self.u * lens.x + self.v * lens.y;
+                Ray::new(origin, self.lower_left_corner + u * self.horizontal + v * self.vertical - origin)
+            }
+            Projection::Orthographic { .. } => {
+                Ray::new(self.lower_left_corner + u * self.horizontal + v * self.vertical, -self.w)
+            }
+            Projection::Equirectangular => {
+                // longitude goes around from behind the camera, so the view
+                // direction ends up in the middle of the image
+                let longitude = 2.0 * std::f32::consts::PI * u;
+                let latitude = std::f32::consts::PI * (v - 0.5);
+                let direction = latitude.cos() * (longitude.cos() * self.w - longitude.sin() * self.u)
+                    + latitude.sin() * self.v;
+                Ray::new(self.origin, direction)
+            }
+        }
+    }
+
+    // how wide a pixel of an image that many pixels across is at a distance
+    pub(crate) fn footprint(&self, distance: f32, width: usize) -> f32 {
+        let across = self.horizontal.length() / width as f32;
+        match self.projection {
+            Projection::Perspective => across / self.depth(self.lower_left_corner) * distance,
+            Projection::Orthographic { .. } => across,
+            Projection::Equirectangular => 2.0 * std::f32::consts::PI / width as f32 * distance,
+        }
+    }
+
+    // how far in front of the camera a point is
+    pub(crate) fn depth(&self, p: Vec3) -> f32 {
+        (self.origin - p).dot(self.w)
+    }
+
+    // where a point ends up in the image, in the same 0 to 1 coordinates that
+    // ray takes, or None when it is behind the camera; panoramas aren't supported
+    pub(crate) fn project(&self, p: Vec3) -> Option<(f32, f32)> {
+        let on_plane = match self.projection {
+            Projection::Perspective if self.depth(p) > 0.0 => {
+                // scaled onto the image plane, which the lower left corner lies on
+                let plane_depth = self.depth(self.lower_left_corner);
+                self.origin + (p - self.origin) * (plane_depth / self.depth(p))
+            }
+            Projection::Orthographic { .. } if self.depth(p) >= 0.0 => p,
+            _ => return None,
+        };
+        let offset = on_plane - self.lower_left_corner;
+        Some((offset.dot(self.horizontal) / self.horizontal.squared_length(),
+              offset.dot(self.vertical) / self.vertical.squared_length()))
+    }
+}
+
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct CameraSettings {
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub projection: Projection,
+    pub origin: Vec3,
+    pub look_at: Vec3,
+    #[cfg_attr(feature = "serde", serde(default = "CameraSettings::default_up"))]
+    pub up: Vec3,
+    // unused by the orthographic projection
+    pub vertical_fov: f32,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub aperture: f32,
+    // the lens opening is a polygon with this many sides, which out of focus
+    // highlights take the shape of, and round below three
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub aperture_blades: u32,
+    // defaults to the distance to look_at
+    pub focus_dist: Option<f32>,
+    // degrees the camera is tilted counterclockwise around its view direction,
+    // which turns the picture clockwise
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub roll: f32,
+}
+
+impl CameraSettings {
+    #[cfg(feature = "serde")]
+    fn default_up() -> Vec3 {
+        Vec3::new(0.0, 1.0, 0.0)
+    }
+
+    pub fn camera(&self, aspect_ratio: f32) -> Camera {
+        let focus_dist = self.focus_dist.unwrap_or_else(|| (self.origin - self.look_at).length());
+        Camera::new(self.projection, self.origin, self.look_at, self.up, self.roll, self.vertical_fov, aspect_ratio, self.aperture,
+                    self.aperture_blades, focus_dist)
+    }
+
+    // moves the camera and its target along the view direction and sideways
+    pub fn translate(&mut self, forward: f32, right: f32) {
+        let direction = (self.look_at - self.origin).unit_vector();
+        let offset = forward * direction + right * direction.cross(self.up).unit_vector();
+        self.origin += offset;
+        self.look_at += offset;
+    }
+
+    // turns the target around the camera, angles in degrees
+    pub fn rotate(&mut self, yaw: f32, pitch: f32) {
+        let direction = self.look_at - self.origin;
+        let right = direction.cross(self.up).unit_vector();
+        let direction = direction.rotated(self.up.unit_vector(), -yaw);
+        let pitched = direction.rotated(right, pitch);
+
+        // stop short of looking straight up or down, the basis breaks down there
+        let direction = if pitched.unit_vector().dot(self.up.unit_vector()).abs() < 0.99 {
+            pitched
+        } else {
+            direction
+        };
+        self.look_at = self.origin + direction;
+    }
+
+    // moved around look_at about the up axis, keeping the same distance
+    pub fn orbited(&self, degrees: f32) -> CameraSettings {
+        let origin = self.look_at + (self.origin - self.look_at).rotated(self.up.unit_vector(), degrees);
+        CameraSettings { origin, ..*self }
+    }
+
+    // moved to t between 0 and 1 along the path through the positions, each
+    // stretch between two of them taking the same time
+    pub fn along(&self, positions: &[Vec3], t: f32) -> CameraSettings {
+        let origin = match positions {
+            [] => self.origin,
+            [position] => *position,
+            _ => {
+                let scaled = clamped(t, 0.0, 1.0) * (positions.len() - 1) as f32;
+                let i = (scaled as usize).min(positions.len() - 2);
+                let f = scaled - i as f32;
+                positions[i] * (1.0 - f) + positions[i + 1] * f
+            }
+        };
+        CameraSettings { origin, ..*self }
+    }
+
+    // Camera::new builds its basis from these, which comes out as NaN or
+    // infinities for a camera without a direction or a field of view that
+    // doesn't fit in front of it
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let direction = self.look_at - self.origin;
+        if direction.near_zero() {
+            return Err(format!("the camera at {} is at its look_at, it has no direction to look in", self.origin).into());
+        }
+        if direction.cross(self.up).near_zero() {
+            return Err(format!("the camera's up {} can't point along its view direction", self.up).into());
+        }
+        let fov = self.vertical_fov;
+        if matches!(self.projection, Projection::Perspective) && (fov.is_nan() || fov <= 0.0 || fov >= 180.0) {
+            return Err(format!("vertical_fov must be between 0 and 180 degrees, not {}", fov).into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub struct Scene {
+    pub camera: CameraSettings,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub settings: RenderSettings,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub spheres: Vec<Sphere>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub planes: Vec<Plane>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub triangles: Vec<Triangle>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub rects_xy: Vec<RectXY>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub rects_xz: Vec<RectXZ>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub rects_yz: Vec<RectYZ>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub cuboids: Vec<Cuboid>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub cylinders: Vec<Cylinder>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub disks: Vec<Disk>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub meshes: Vec<Mesh>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub media: Vec<Medium>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub point_lights: Vec<PointLight>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub spot_lights: Vec<SpotLight>,
+    // camera positions an animation passes through, looking at the camera's look_at
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub keyframes: Vec<Vec3>,
+}
+
+impl Scene {
+    // a scene with nothing in it yet
+    pub fn new(camera: CameraSettings) -> Scene {
+        Scene {
+            camera,
+            settings: RenderSettings::default(),
+            spheres: Vec::new(),
+            planes: Vec::new(),
+            triangles: Vec::new(),
+            rects_xy: Vec::new(),
+            rects_xz: Vec::new(),
+            rects_yz: Vec::new(),
+            cuboids: Vec::new(),
+            cylinders: Vec::new(),
+            disks: Vec::new(),
+            meshes: Vec::new(),
+            media: Vec::new(),
+            point_lights: Vec::new(),
+            spot_lights: Vec::new(),
+            keyframes: Vec::new(),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn from_file(path: &str) -> Result<Scene, Box<dyn Error>> {
+        let scene: Scene = toml::from_str(&std::fs::read_to_string(path)?)?;
+        scene.validate()?;
+        Ok(scene)
+    }
+
+    // the same layout as the scene files, as json
+    #[cfg(feature = "serde")]
+    pub fn from_json(text: &str) -> Result<Scene, Box<dyn Error>> {
+        let scene: Scene = serde_json::from_str(text)?;
+        scene.validate()?;
+        Ok(scene)
+    }
+
+    // the camera has to work from every keyframe too
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        self.camera.validate()?;
+        for &origin in &self.keyframes {
+            CameraSettings { origin, ..self.camera }.validate()?;
+        }
+        Ok(())
+    }
+
+    // with packets the spheres are grouped into sphere packets before they go
+    // into the bvh, which so far only pays off with wide vector units: a
+    // baseline x86-64 build is slower with them on the random-many preset
+    pub fn world(self, packets: bool, build: BvhBuild) -> Result<World, Box<dyn Error>> {
+        let mut objects: Vec<Box<dyn Hittable>> = Vec::new();
+        let mut lights: Vec<Box<dyn Hittable>> = Vec::new();
+        for p in self.planes {
+            // run the normal through the constructor so it is normalized
+            objects.push(Box::new(Plane::new(p.point, p.normal, p.material)));
+        }
+        let silhouettes = self.spheres.iter().map(|s| (s.center, s.radius)).collect();
+        for s in &self.spheres {
+            if s.material.is_emissive() {
+                lights.push(Box::new(s.clone()));
+            }
+        }
+        if packets && !self.spheres.is_empty() {
+            for p in SpherePacket::build(self.spheres) {
+                objects.push(Box::new(p));
+            }
+        } else {
+            for s in self.spheres {
+                objects.push(Box::new(s));
+            }
+        }
+        for t in self.triangles {
+            objects.push(Box::new(t));
+        }
+        for r in self.rects_xy {
+            let rect = r.rect();
+            if rect.material.is_emissive() {
+                lights.push(Box::new(rect.clone()));
+            }
+            objects.push(Box::new(rect));
+        }
+        for r in self.rects_xz {
+            let rect = r.rect();
+            if rect.material.is_emissive() {
+                lights.push(Box::new(rect.clone()));
+            }
+            objects.push(Box::new(rect));
+        }
+        for r in self.rects_yz {
+            let rect = r.rect();
+            if rect.material.is_emissive() {
+                lights.push(Box::new(rect.clone()));
+            }
+            objects.push(Box::new(rect));
+        }
+        for c in self.cuboids {
+            // builds the faces, which aren't part of the scene file
+            let mut object: Box<dyn Hittable> = Box::new(Cuboid::new(c.min, c.max, c.material));
+            if c.rotate_y != 0.0 {
+                object = Box::new(RotateY::new(object, c.rotate_y));
+            }
+            if !c.translate.near_zero() {
+                object = Box::new(Translate::new(object, c.translate));
+            }
+            objects.push(object);
+        }
+        for c in self.cylinders {
+            objects.push(Box::new(Cylinder::new(c.base, c.axis, c.radius, c.height, c.material, c.capped)));
+        }
+        for d in self.disks {
+            let disk = Disk::new(d.center, d.normal, d.radius, d.material);
+            if disk.material.is_emissive() {
+                lights.push(Box::new(disk.clone()));
+            }
+            objects.push(Box::new(disk));
+        }
+
+        // a mesh gets one box around all of its triangles in the overlay
+        let mut bounds: Vec<Aabb> = objects.iter().filter_map(|o| o.bounding_box()).collect();
+        let mut warnings = Vec::new();
+        for m in &self.meshes {
+            let mut mesh_bounds = None;
+            for t in m.triangles(&mut warnings)? {
+                mesh_bounds = match (mesh_bounds, t.bounding_box()) {
+                    (Some(a), Some(b)) => Some(Aabb::surrounding_box(a, b)),
+                    (a, b) => a.or(b),
+                };
+                objects.push(Box::new(t));
+            }
+            bounds.extend(mesh_bounds);
+        }
+        for m in self.media {
+            let medium = m.constant_medium();
+            bounds.extend(medium.bounding_box());
+            objects.push(Box::new(medium));
+        }
+        let mut world = World::new(objects, lights, self.point_lights, self.spot_lights, bounds, silhouettes, build);
+        world.warnings = warnings;
+        Ok(world)
+    }
+}
+
+pub fn default_scene() -> Scene {
+    Scene {
+        spheres: vec![
+            Sphere::new(Vec3::new(-1.0, 0.3, 0.0), 0.3, Material::Metal { albedo: Vec3::new(0.6, 0.6, 0.6), fuzz: 0.4 }),
+            Sphere::new(Vec3::new(0.0, 0.5, 0.0), 0.5, Material::Lambertian { albedo: Texture::Solid(Vec3::new(0.9, 0.2, 0.2)) }),
+            Sphere::new(Vec3::new(1.0, 0.5, 0.0), 0.5, Material::Metal { albedo: Vec3::new(0.4, 0.4, 0.8), fuzz: 0.0 }),
+            Sphere::new(Vec3::new(-0.4, 0.25, 0.8), 0.25, Material::Dielectric { refraction_index: 1.5, dispersion: 0.0, absorption: Vec3::zero() }),
+            Sphere::new(Vec3::new(0.6, 0.15, 0.9), 0.15, Material::Emissive { emit: Vec3::new(1.0, 0.9, 0.6), two_sided: true }),
+        ],
+        planes: vec![
+            Plane::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), Material::Lambertian { albedo: Texture::Solid(Vec3::new(0.8, 0.8, 0.0)) }),
+        ],
+        ..Scene::new(CameraSettings {
+            projection: Projection::Perspective,
+            origin: Vec3::new(0.0, 2.0, 2.0),
+            look_at: Vec3::new(0.0, 0.0, 0.0),
+            up: Vec3::new(0.0, 1.0, 0.0),
+            vertical_fov: 60.0,
+            aperture: 0.1,
+            aperture_blades: 0,
+            roll: 0.0,
+            // focus on the middle sphere
+            focus_dist: Some(2.5),
+        })
+    }
+}
+
+// built in scenes to try things out and compare timings with
+#[derive(Clone, Copy)]
+pub enum ScenePreset {
+    Default,
+    ThreeSpheres,
+    RandomMany,
+    Cornell,
+}
+
+impl std::str::FromStr for ScenePreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ScenePreset, String> {
+        match s {
+            "default" => Ok(ScenePreset::Default),
+            "three-spheres" => Ok(ScenePreset::ThreeSpheres),
+            "random-many" => Ok(ScenePreset::RandomMany),
+            "cornell" => Ok(ScenePreset::Cornell),
+            _ => Err(format!("unknown scene preset: {}", s)),
+        }
+    }
+}
+
+impl ScenePreset {
+    // the seed only changes the random scene
+    pub fn scene(self, seed: u64) -> Scene {
+        match self {
+            ScenePreset::Default => default_scene(),
+            ScenePreset::ThreeSpheres => three_spheres_scene(),
+            ScenePreset::RandomMany => random_many_scene(seed),
+            ScenePreset::Cornell => cornell_scene(),
+        }
+    }
+}
+
+// glass, diffuse and metal side by side on a checkered ground
+fn three_spheres_scene() -> Scene {
+    Scene {
+        spheres: vec![
+            Sphere::new(Vec3::new(-1.0, 0.5, -1.0), 0.5, Material::Dielectric { refraction_index: 1.5, dispersion: 0.0, absorption: Vec3::zero() }),
+            Sphere::new(Vec3::new(0.0, 0.5, -1.0), 0.5, Material::Lambertian { albedo: Texture::Solid(Vec3::new(0.1, 0.2, 0.5)) }),
+            Sphere::new(Vec3::new(1.0, 0.5, -1.0), 0.5, Material::Metal { albedo: Vec3::new(0.8, 0.6, 0.2), fuzz: 0.1 }),
+        ],
+        planes: vec![
+            // just below zero, checkers are undecided on the axis planes
+            Plane::new(Vec3::new(0.0, -0.001, 0.0), Vec3::new(0.0, 1.0, 0.0), Material::Lambertian {
+                albedo: Texture::Checker { odd: Vec3::new(0.2, 0.3, 0.1), even: Vec3::splat(0.9), scale: 3.0 },
+            }),
+        ],
+        ..Scene::new(CameraSettings {
+            projection: Projection::Perspective,
+            origin: Vec3::new(0.0, 1.0, 2.0),
+            look_at: Vec3::new(0.0, 0.5, -1.0),
+            up: Vec3::new(0.0, 1.0, 0.0),
+            vertical_fov: 50.0,
+            aperture: 0.0,
+            aperture_blades: 0,
+            roll: 0.0,
+            focus_dist: None,
+        })
+    }
+}
+
+// the field of small random spheres around three large ones, which has enough
+// objects to show what the bvh is worth
+fn random_many_scene(seed: u64) -> Scene {
+    let mut random = StdRng::seed_from_u64(seed);
+    let mut spheres = vec![
+        Sphere::new(Vec3::new(0.0, 1.0, 0.0), 1.0, Material::Dielectric { refraction_index: 1.5, dispersion: 0.0, absorption: Vec3::zero() }),
+        Sphere::new(Vec3::new(-4.0, 1.0, 0.0), 1.0, Material::Lambertian { albedo: Texture::Solid(Vec3::new(0.4, 0.2, 0.1)) }),
+        Sphere::new(Vec3::new(4.0, 1.0, 0.0), 1.0, Material::Metal { albedo: Vec3::new(0.7, 0.6, 0.5), fuzz: 0.0 }),
+    ];
+
+    for a in -11..11 {
+        for b in -11..11 {
+            let center = Vec3::new(a as f32 + 0.9 * random.gen::<f32>(), 0.2, b as f32 + 0.9 * random.gen::<f32>());
+            // keep clear of the large spheres
+            if spheres.iter().take(3).any(|s| (center - s.center).length() < s.radius + 0.2) {
+                continue;
+            }
+
+            let choice: f32 = random.gen();
+            let material = if choice < 0.8 {
+                let color = Vec3::new(random.gen(), random.gen(), random.gen()) * Vec3::new(random.gen(), random.gen(), random.gen());
+                Material::Lambertian { albedo: Texture::Solid(color) }
+            } else if choice < 0.95 {
+                let albedo = Vec3::new(random.gen_range(0.5, 1.0), random.gen_range(0.5, 1.0), random.gen_range(0.5, 1.0));
+                Material::Metal { albedo, fuzz: random.gen_range(0.0, 0.5) }
+            } else {
+                Material::Dielectric { refraction_index: 1.5, dispersion: 0.0, absorption: Vec3::zero() }
+            };
+            spheres.push(Sphere::new(center, 0.2, material));
+        }
+    }
+
+    Scene {
+        spheres,
+        planes: vec![
+            Plane::new(Vec3::new(0.0, -0.001, 0.0), Vec3::new(0.0, 1.0, 0.0), Material::Lambertian {
+                albedo: Texture::Checker { odd: Vec3::new(0.2, 0.3, 0.1), even: Vec3::splat(0.9), scale: 3.0 },
+            }),
+        ],
+        ..Scene::new(CameraSettings {
+            projection: Projection::Perspective,
+            origin: Vec3::new(13.0, 2.0, 3.0),
+            look_at: Vec3::new(0.0, 0.0, 0.0),
+            up: Vec3::new(0.0, 1.0, 0.0),
+            vertical_fov: 20.0,
+            aperture: 0.1,
+            aperture_blades: 0,
+            roll: 0.0,
+            focus_dist: Some(10.0),
+        })
+    }
+}
+
+// the cornell box with two lights in the ceiling and two turned boxes
+fn cornell_scene() -> Scene {
+    let white = || Material::Lambertian { albedo: Texture::Solid(Vec3::splat(0.73)) };
+    let light = || Material::Emissive { emit: Vec3::splat(15.0), two_sided: true };
+    let wall = |y0, y1, z0, z1, k, material| RectYZ { y0, y1, z0, z1, k, material, flip: false };
+    let flat = |x0, x1, z0, z1, k, material| RectXZ { x0, x1, z0, z1, k, material, flip: false };
+    let cuboid = |max, rotate_y, translate| Cuboid { min: Vec3::zero(), max, material: white(), rotate_y, translate, faces: Vec::new() };
+
+    let mut scene = Scene {
+        rects_yz: vec![
+            wall(0.0, 555.0, 0.0, 555.0, 555.0, Material::Lambertian { albedo: Texture::Solid(Vec3::new(0.12, 0.45, 0.15)) }),
+            wall(0.0, 555.0, 0.0, 555.0, 0.0, Material::Lambertian { albedo: Texture::Solid(Vec3::new(0.65, 0.05, 0.05)) }),
+        ],
+        rects_xz: vec![
+            flat(113.0, 243.0, 227.0, 332.0, 554.0, light()),
+            flat(313.0, 443.0, 227.0, 332.0, 554.0, light()),
+            flat(0.0, 555.0, 0.0, 555.0, 0.0, white()),
+            flat(0.0, 555.0, 0.0, 555.0, 555.0, white()),
+        ],
+        rects_xy: vec![
+            RectXY { x0: 0.0, x1: 555.0, y0: 0.0, y1: 555.0, k: 555.0, material: white(), flip: false },
+        ],
+        cuboids: vec![
+            cuboid(Vec3::new(165.0, 330.0, 165.0), 15.0, Vec3::new(265.0, 0.0, 295.0)),
+            cuboid(Vec3::new(165.0, 165.0, 165.0), -18.0, Vec3::new(130.0, 0.0, 65.0)),
+        ],
+        ..Scene::new(CameraSettings {
+            projection: Projection::Perspective,
+            origin: Vec3::new(278.0, 278.0, -800.0),
+            look_at: Vec3::new(278.0, 278.0, 0.0),
+            up: Vec3::new(0.0, 1.0, 0.0),
+            vertical_fov: 40.0,
+            aperture: 0.0,
+            aperture_blades: 0,
+            roll: 0.0,
+            focus_dist: None,
+        })
+    };
+    // nothing but the lights inside the box
+    scene.settings.environment = Environment::Solid(Vec3::zero());
+    scene
+}
+
+// what rays see when they miss everything, given like textures in scene files:
+// a plain array, { top = .., bottom = .. } or { map = "sky.hdr" }
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(untagged))]
+pub enum Environment {
+    Solid(Vec3),
+    Gradient { top: Vec3, bottom: Vec3 },
+    // an equirectangular image around the scene
+    EnvMap { map: ImageTexture },
+}
+
+impl Default for Environment {
+    fn default() -> Environment {
+        Environment::Gradient { top: Vec3::new(0.5, 0.7, 1.0), bottom: Vec3::splat(1.0) }
+    }
+}
+
+impl Environment {
+    pub fn value(&self, direction: Vec3) -> Vec3 {
+        let direction = direction.unit_vector();
+        match self {
+            Environment::Solid(color) => *color,
+            Environment::Gradient { top, bottom } => Vec3::lerp(*bottom, *top, (direction.y + 1.0) * 0.5),
+            Environment::EnvMap { map } => {
+                let (u, v) = Sphere::uv(direction);
+                map.value(u, v)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera() -> CameraSettings {
+        CameraSettings {
+            projection: Projection::Perspective,
+            origin: Vec3::new(0.0, 1.0, 3.0),
+            look_at: Vec3::zero(),
+            up: Vec3::new(0.0, 1.0, 0.0),
+            vertical_fov: 60.0,
+            aperture: 0.0,
+            aperture_blades: 0,
+            focus_dist: None,
+            roll: 0.0,
+        }
+    }
+
+    #[test]
+    fn degenerate_cameras_are_rejected() {
+        assert!(camera().validate().is_ok());
+        assert!(CameraSettings { look_at: camera().origin, ..camera() }.validate().is_err());
+        // up along the view direction, either way round
+        let direction = camera().look_at - camera().origin;
+        assert!(CameraSettings { up: direction * 2.0, ..camera() }.validate().is_err());
+        assert!(CameraSettings { up: -direction, ..camera() }.validate().is_err());
+
+        for fov in [0.0, 180.0, -10.0, 200.0, f32::NAN] {
+            assert!(CameraSettings { vertical_fov: fov, ..camera() }.validate().is_err(), "fov {}", fov);
+        }
+        for fov in [0.1, 179.9] {
+            assert!(CameraSettings { vertical_fov: fov, ..camera() }.validate().is_ok(), "fov {}", fov);
+        }
+        // the orthographic projection has no field of view
+        let orthographic = CameraSettings { projection: Projection::Orthographic { scale: 2.0 }, vertical_fov: 0.0, ..camera() };
+        assert!(orthographic.validate().is_ok());
+    }
+
+    #[test]
+    fn keyframes_are_validated_like_the_camera() {
+        let mut scene = Scene::new(camera());
+        scene.keyframes = vec![Vec3::new(3.0, 1.0, 0.0)];
+        assert!(scene.validate().is_ok());
+        scene.keyframes.push(Vec3::zero());
+        assert!(scene.validate().is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn scene_file_survives_a_round_trip() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/scenes/default.toml");
+        let scene = Scene::from_file(path).unwrap();
+        assert_eq!(scene.spheres.len(), 5);
+        assert_eq!(scene.camera.vertical_fov, 60.0);
+
+        // written out again as json and read back in
+        let value: toml::Value = toml::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let again = Scene::from_json(&serde_json::to_string(&value).unwrap()).unwrap();
+        assert_eq!(again.spheres.len(), scene.spheres.len());
+        assert_eq!(again.camera.vertical_fov, scene.camera.vertical_fov);
+        assert_eq!(again.spheres[0].center, scene.spheres[0].center);
+    }
+
+    #[test]
+    fn spot_lights_fall_off_between_the_cones() {
+        let spot = SpotLight {
+            position: Vec3::zero(),
+            direction: Vec3::new(0.0, -1.0, 0.0),
+            intensity: Vec3::splat(1.0),
+            inner_angle: 20.0,
+            outer_angle: 30.0,
+        };
+        // a point on the floor one below the light, the given degrees off its axis
+        let at = |degrees: f32| Vec3::new(degrees.to_radians().tan(), -1.0, 0.0);
+        let middle = ((20f32.to_radians().cos() + 30f32.to_radians().cos()) / 2.0).acos().to_degrees();
+        for &(degrees, expected) in &[(0.0, 1.0), (10.0, 1.0), (20.0, 1.0), (middle, 0.5), (30.0, 0.0), (45.0, 0.0)] {
+            let falloff = spot.falloff(at(degrees));
+            assert!((falloff - expected).abs() < 1e-3, "{} at {} degrees, {} expected", falloff, degrees, expected);
+        }
+        assert!(spot.falloff(at(21.0)) < 1.0 && spot.falloff(at(29.0)) > 0.0);
+        assert!(spot.falloff(at(22.0)) > spot.falloff(at(24.0)));
+
+        // nothing reaches the floor outside of the outer cone
+        let mut scene = Scene::new(camera());
+        scene.spot_lights.push(spot);
+        let world = scene.world(false, BvhBuild::Median).unwrap();
+        let mut random = StdRng::seed_from_u64(0);
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        assert_eq!(world.direct_lighting(at(45.0), up, 0.001, &mut random), Vec3::zero());
+        assert_eq!(world.direct_lighting(at(0.0), up, 0.001, &mut random), Vec3::splat(1.0));
+    }
+
+    #[test]
+    fn sphere_packets_find_the_same_hits_as_single_spheres() {
+        let single = ScenePreset::RandomMany.scene(0).world(false, BvhBuild::Sah).unwrap();
+        let packets = ScenePreset::RandomMany.scene(0).world(true, BvhBuild::Sah).unwrap();
+        let mut random = StdRng::seed_from_u64(64);
+        let mut sphere_hits = 0;
+        for _ in 0..5000 {
+            let origin = Vec3::new(random.gen_range(-14.0, 14.0), random.gen_range(0.1, 4.0), random.gen_range(-14.0, 14.0));
+            let target = Vec3::new(random.gen_range(-11.0, 11.0), random.gen_range(0.0, 1.0), random.gen_range(-11.0, 11.0));
+            let ray = Ray::new(origin, target - origin);
+            let expected = single.hit_test(&ray, 0.001, f32::MAX, &mut random);
+            let found = packets.hit_test(&ray, 0.001, f32::MAX, &mut random);
+            match (expected, found) {
+                (None, None) => {}
+                (Some(expected), Some(found)) => {
+                    assert!((expected.t - found.t).abs() <= 1e-4 * expected.t.max(1.0), "{} against {}", found.t, expected.t);
+                    assert_approx_eq!(found.normal, expected.normal, 1e-4);
+                    assert_approx_eq!(found.position, expected.position, 1e-4 * expected.t.max(1.0));
+                    sphere_hits += (expected.position.y > 0.01) as usize;
+                }
+                (expected, found) => panic!("{:?} with single spheres, {:?} with packets", expected.map(|h| h.t), found.map(|h| h.t)),
+            }
+        }
+        assert!(sphere_hits > 1000, "{} rays hit a sphere", sphere_hits);
+    }
+}